@@ -1,4 +1,10 @@
+use std::path::PathBuf;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("./protos/orderbook.proto")?;
+    // Descriptors of the service for gRPC server reflection
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR")?);
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("orderbook_descriptor.bin"))
+        .compile(&["./protos/orderbook.proto"], &["./protos"])?;
     Ok(())
 }
@@ -1,53 +1,507 @@
-use anyhow::{Context, Error, Result};
-
-use crate::orderbook::{Level, Summary};
+use anyhow::{anyhow, bail, Context, Error, Result};
+use futures_util::{SinkExt, StreamExt};
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::Sender;
+use tokio::sync::watch::{self, Receiver};
+use tokio::time::{Duration, Instant};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tracing::{debug, info, info_span, warn, Instrument};
 
 pub mod binance;
+pub mod bitfinex;
 pub mod bitstamp;
+pub mod book;
+pub mod bybit;
+pub mod coinbase;
+pub mod deribit;
+pub mod generic;
+pub mod htx;
+pub mod keepalive;
+pub mod kraken;
+pub mod okx;
+pub mod reconnect;
+pub mod symbol;
+
+use crate::metrics::FeedMetrics;
+use keepalive::Keepalive;
+use reconnect::{Reconnect, ReconnectPolicy, ReconnectStats};
+
+// Common interface implemented by every exchange order book feed
+#[tonic::async_trait]
+pub trait Exchange: Send {
+    // Name used to tag levels in the merged order book
+    fn name(&self) -> &str;
+
+    // Websocket url to connect to for a subscription of `depth` levels
+    fn url(&self, depth: usize) -> String;
+
+    // Messages to send right after connecting, also resets any state of the previous connection
+    fn subscribe(&mut self, depth: usize) -> Vec<Message>;
+
+    // Levels the exchange is subscribed to when `depth` levels are needed,
+    // the connection is re-established when a larger depth changes it
+    fn subscribed_depth(&self, depth: usize) -> usize {
+        depth
+    }
+
+    // Parse a websocket message into a book of at most `depth` levels, None if it does not carry an order book.
+    // Errors skip the message, except a `Disconnect` which closes the connection
+    async fn parse(&mut self, msg: Message, depth: usize) -> Result<Option<Book>>;
+
+    // Messages to send back after parsing a message, e.g. to resubscribe when the book is out of sync
+    fn replies(&mut self) -> Vec<Message> {
+        Vec::new()
+    }
+
+    // Books reset by a snapshot in the middle of the stream since the last call
+    fn resyncs(&mut self) -> u64 {
+        0
+    }
+
+    // Message sent every ping interval to keep the connection alive, a websocket ping unless
+    // the exchange expects its own
+    fn keepalive(&mut self) -> Message {
+        Message::Ping(Vec::new())
+    }
+}
+
+// Parse error the current connection cannot recover from, the feed reconnects instead of
+// skipping the message
+#[derive(Debug)]
+pub struct Disconnect(pub String);
+
+impl std::fmt::Display for Disconnect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Disconnect {}
+
+// Builds an exchange feed for a trade pair from its config
+type Builder = fn(&ExchangeConfig, &str) -> Result<Box<dyn Exchange>>;
+
+// Supported exchanges, looked up by the name given in the config
+const REGISTRY: &[(&str, Builder)] = &[
+    ("binance", binance::BinanceExchange::build),
+    ("bitfinex", bitfinex::BitfinexExchange::build),
+    ("bitstamp", bitstamp::BitstampExchange::build),
+    ("bybit", bybit::BybitExchange::build),
+    ("coinbase", coinbase::CoinbaseExchange::build),
+    ("deribit", deribit::DeribitExchange::build),
+    ("generic", generic::GenericExchange::build),
+    ("htx", htx::HtxExchange::build),
+    ("kraken", kraken::KrakenExchange::build),
+    ("okx", okx::OkxExchange::build),
+];
+
+// Build the feed of a configured exchange for a trade pair
+pub fn build(config: &ExchangeConfig, symbol: &str) -> Result<Box<dyn Exchange>> {
+    let (_, builder) = REGISTRY
+        .iter()
+        .find(|(name, _)| *name == config.name)
+        .ok_or_else(|| anyhow!("Unknown exchange: {}", config.name))?;
+
+    builder(config, symbol)
+}
+
+// Update pushed by an exchange feed to the merger
+#[derive(Clone, Debug)]
+pub enum Feed {
+    Book(Book),
+    // Connected and subscribed, waiting for the first book
+    Connected(ReconnectStats),
+    // Connection failed or lost, the last book is no longer valid
+    Disconnected(ReconnectStats),
+}
+
+// Startup state of a feed, the error of the last failed connection until the first book
+#[derive(Clone, Debug, Default)]
+enum Startup {
+    #[default]
+    Pending,
+    Failed(String),
+    Ready,
+}
+
+// Handle of a started exchange feed, ready once the first book is received
+#[derive(Debug)]
+pub struct FeedHandle {
+    name: String,
+    ready: watch::Receiver<Startup>,
+}
+
+impl FeedHandle {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Wait for the first book, false if none came in within `timeout`
+    pub async fn ready(&mut self, timeout: Duration) -> bool {
+        let ready = self
+            .ready
+            .wait_for(|startup| matches!(startup, Startup::Ready));
+        matches!(tokio::time::timeout(timeout, ready).await, Ok(Ok(_)))
+    }
+
+    // Error of the last failed connection while waiting for the first book
+    pub fn error(&self) -> Option<String> {
+        match &*self.ready.borrow() {
+            Startup::Failed(err) => Some(err.clone()),
+            _ => None,
+        }
+    }
+}
+
+// One process to fetch exchange order books and push them to channel, `depth`
+// follows the number of levels needed by the subscribers
+pub fn start(
+    exchange: Box<dyn Exchange>,
+    policy: ReconnectPolicy,
+    keepalive: Keepalive,
+    metrics: FeedMetrics,
+    sender: Sender<Feed>,
+    depth: Receiver<usize>,
+) -> FeedHandle {
+    let (ready, receiver) = watch::channel(Startup::default());
+    let handle = FeedHandle {
+        name: exchange.name().to_string(),
+        ready: receiver,
+    };
+
+    // Created in the span of the caller, e.g. the trade pair
+    let span = info_span!("feed", exchange = %handle.name);
+    let task = FeedTask {
+        exchange,
+        sender,
+        ready,
+        depth,
+        reconnect: Reconnect::new(policy),
+        keepalive,
+        metrics,
+    };
+    tokio::spawn(task.run().instrument(span));
+
+    handle
+}
+
+// State of a feed kept across its connections
+struct FeedTask {
+    exchange: Box<dyn Exchange>,
+    sender: Sender<Feed>,
+    ready: watch::Sender<Startup>,
+    depth: Receiver<usize>,
+    reconnect: Reconnect,
+    keepalive: Keepalive,
+    metrics: FeedMetrics,
+}
+
+impl FeedTask {
+    async fn run(mut self) {
+        for attempt in 1.. {
+            let connection = self.connection();
+            match connection
+                .instrument(info_span!("connection", attempt))
+                .await
+            {
+                Ok(true) => {}
+                // Nobody can subscribe anymore
+                Ok(false) => return,
+                Err(err) => {
+                    self.metrics.reconnects.inc();
+                    let delay = self.reconnect.failed();
+                    let stats = self.reconnect.stats();
+                    if stats.down {
+                        warn!(
+                            error = format!("{err:#}"),
+                            failures = stats.consecutive_failures,
+                            ?delay,
+                            "exchange down, circuit breaker open"
+                        );
+                    } else {
+                        warn!(error = format!("{err:#}"), ?delay, "reconnecting");
+                    }
+                    self.ready.send_if_modified(|startup| match startup {
+                        Startup::Ready => false,
+                        _ => {
+                            *startup = Startup::Failed(format!("{err:#}"));
+                            true
+                        }
+                    });
+                    _ = self.sender.send(Feed::Disconnected(stats));
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    // Connect, subscribe and push the books of one connection. Ok(true) to subscribe again for
+    // a larger depth, Ok(false) once nobody can subscribe anymore
+    async fn connection(&mut self) -> Result<bool> {
+        let Self {
+            exchange,
+            sender,
+            ready,
+            depth,
+            reconnect,
+            keepalive,
+            metrics,
+        } = self;
+        let required = *depth.borrow_and_update();
+        let subscribed = exchange.subscribed_depth(required);
+        let url = exchange.url(required);
+
+        // connect to exchange websocket
+        let (mut ws_write, mut ws_read) = connect_async(&url)
+            .await
+            .context("connection failure")?
+            .0
+            .split();
+
+        // Send subscription messages on the websocket
+        for msg in exchange.subscribe(required) {
+            ws_write.send(msg).await.context("subscription failure")?;
+        }
+        reconnect.connected();
+        _ = sender.send(Feed::Connected(reconnect.stats()));
+        info!(%url, depth = subscribed, "connected");
+
+        // Listen to messages, any message including pongs shows the connection is alive
+        let mut live = false;
+        let mut ping =
+            tokio::time::interval_at(Instant::now() + keepalive.interval, keepalive.interval);
+        let idle = tokio::time::sleep(keepalive.idle_timeout);
+        tokio::pin!(idle);
+        loop {
+            tokio::select! {
+                msg = ws_read.next() => {
+                    let Some(msg) = msg else { bail!("connection closed") };
+                    let Ok(msg) = msg else { continue };
+                    metrics.messages.inc();
+                    idle.as_mut().reset(Instant::now() + keepalive.idle_timeout);
+                    if let Message::Pong(_) = msg {
+                        metrics.pongs.inc();
+                        debug!("pong received");
+                        continue;
+                    }
+                    let received = now_micros();
+                    let levels = *depth.borrow();
+                    match exchange.parse(msg, levels).await {
+                        Ok(Some(mut book)) => {
+                            if !live {
+                                live = true;
+                                reconnect.recovered();
+                                ready.send_replace(Startup::Ready);
+                            }
+                            book.receive_time = received;
+                            // send the orderbook to channel
+                            _ = sender.send(Feed::Book(book));
+                        }
+                        Ok(None) => {}
+                        Err(err) => {
+                            metrics.parse_failures.inc();
+                            if err.is::<Disconnect>() {
+                                return Err(err);
+                            }
+                            warn!(error = format!("{err:#}"), "message parse failure");
+                        }
+                    }
+
+                    metrics.resyncs.inc_by(exchange.resyncs());
+                    for reply in exchange.replies() {
+                        ws_write.send(reply).await.context("reply failure")?;
+                    }
+                }
+                _ = ping.tick() => {
+                    ws_write.send(exchange.keepalive()).await.context("ping failure")?;
+                    metrics.pings.inc();
+                    debug!("ping sent");
+                }
+                _ = &mut idle => {
+                    metrics.idle_timeouts.inc();
+                    bail!("no message within the idle timeout of {:?}", keepalive.idle_timeout);
+                }
+                changed = depth.changed() => {
+                    if changed.is_err() {
+                        return Ok(false);
+                    }
+                    // Subscribe again when subscribers need more levels than the subscription provides
+                    let levels = *depth.borrow();
+                    if exchange.subscribed_depth(levels) > subscribed {
+                        info!(depth = levels, "resubscribing for a larger depth");
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Time allowed for a REST snapshot when the config does not set one
+const DEFAULT_SNAPSHOT_TIMEOUT_MS: u64 = 10_000;
+
+// Exchange enabled from the command line as `name[,key=value...]`
+#[derive(Clone, Debug, Default)]
+pub struct ExchangeConfig {
+    pub name: String,
+    pub options: HashMap<String, String>,
+}
+
+impl ExchangeConfig {
+    pub fn option(&self, key: &str) -> Option<&str> {
+        self.options.get(key).map(String::as_str)
+    }
+
+    // Websocket url, falls back to the exchange default
+    pub fn url(&self, default: &str) -> String {
+        self.option("url").unwrap_or(default).to_string()
+    }
+
+    // Client for the REST snapshots of the `diff` modes. Bitstamp fetches them while parsing a
+    // message, when keepalives cannot run, so they time out after `snapshot_timeout_ms`
+    pub fn snapshot_client(&self) -> Result<reqwest::Client> {
+        let millis = match self.option("snapshot_timeout_ms") {
+            Some(value) => value
+                .parse()
+                .with_context(|| format!("Invalid snapshot_timeout_ms '{value}'"))?,
+            None => DEFAULT_SNAPSHOT_TIMEOUT_MS,
+        };
+        if millis == 0 {
+            bail!("snapshot_timeout_ms of {} must be at least 1", self.name);
+        }
+        reqwest::Client::builder()
+            .timeout(Duration::from_millis(millis))
+            .build()
+            .context("Failed to build REST client")
+    }
+}
+
+impl FromStr for ExchangeConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split(',');
+        let name = parts.next().unwrap_or_default().trim().to_lowercase();
+        if name.is_empty() {
+            return Err(anyhow!("Missing exchange name in '{s}'"));
+        }
+
+        let options = parts
+            .map(|part| {
+                let (key, value) = part
+                    .split_once('=')
+                    .with_context(|| format!("Expected key=value, got '{part}'"))?;
+                Ok((key.trim().to_string(), value.trim().to_string()))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { name, options })
+    }
+}
+
+// Price level of an exchange order book
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PriceLevel {
+    pub price: Decimal,
+    pub amount: Decimal,
+}
+
+// Order book of one exchange, bids descending and asks ascending
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Book {
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+    // Exchange event time of the book in microseconds since the epoch, if the exchange sends one
+    pub event_time: Option<u64>,
+    // Exchange sequence number or update id of the book, if the exchange sends one
+    pub sequence: Option<u64>,
+    // Local time the message carrying the book was received, in microseconds since the epoch
+    pub receive_time: u64,
+}
+
+// Smallest of the depths an exchange offers covering `depth`, the largest one when none does
+pub fn covering(depths: &[usize], depth: usize) -> usize {
+    depths
+        .iter()
+        .copied()
+        .find(|&levels| levels >= depth)
+        .unwrap_or(depths[depths.len() - 1])
+}
+
+// Current time in microseconds since the epoch
+pub fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_micros() as u64)
+}
+
+// Parse an exchange decimal, plain or in scientific notation
+pub fn parse_decimal(value: &str) -> Result<Decimal> {
+    Decimal::from_str(value)
+        .or_else(|_| Decimal::from_scientific(value))
+        .with_context(|| format!("Invalid decimal '{value}'"))
+}
 
 // Basic orderbook struct for exchange response
 #[derive(Clone, serde::Deserialize, Debug, serde::Serialize, Default)]
 pub struct Orderbook {
+    #[serde(deserialize_with = "deserialize_levels")]
     pub bids: Vec<[String; 2]>,
+    #[serde(deserialize_with = "deserialize_levels")]
     pub asks: Vec<[String; 2]>,
 }
 
+// Deserialize levels sent as `[price, amount, ...]` arrays of strings or numbers, e.g. the OKX
+// `[price, size, liquidated, orders]`, keeping the price and amount
+pub fn deserialize_levels<'de, D>(deserializer: D) -> Result<Vec<[String; 2]>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    use serde::Deserialize;
+
+    let field = |level: &[serde_json::Value], index: usize| match level.get(index) {
+        Some(serde_json::Value::String(value)) => Ok(value.clone()),
+        Some(serde_json::Value::Number(value)) => Ok(value.to_string()),
+        _ => Err(D::Error::custom(format!("Invalid price level {level:?}"))),
+    };
+    Vec::<Vec<serde_json::Value>>::deserialize(deserializer)?
+        .iter()
+        .map(|level| Ok([field(level, 0)?, field(level, 1)?]))
+        .collect()
+}
+
 impl Orderbook {
-    // convert orderbook to Summary for GRPC
-    pub fn convert(self, exchange: &str) -> Result<Summary> {
-        let mut summary = Summary {
+    // convert orderbook to the exchange Book of at most `depth` levels
+    pub fn convert(self, depth: usize) -> Result<Book> {
+        let book = Book {
             bids: self
                 .bids
                 .iter()
-                .take(10)
-                .map(|b| Self::make_level(exchange, b))
-                .collect::<Result<Vec<Level>, Error>>()
+                .take(depth)
+                .map(Self::make_level)
+                .collect::<Result<Vec<PriceLevel>, Error>>()
                 .context("Failed to parse bids")?,
             asks: self
                 .asks
                 .iter()
-                .take(10)
-                .map(|b| Self::make_level(exchange, b))
-                .collect::<Result<Vec<Level>, Error>>()
+                .take(depth)
+                .map(Self::make_level)
+                .collect::<Result<Vec<PriceLevel>, Error>>()
                 .context("Failed to parse Asks")?,
             ..<_>::default()
         };
 
-        Ok(summary)
+        Ok(book)
     }
 
-    fn make_level(exchange: &str, arr: &[String; 2]) -> Result<Level> {
-        let price = arr[0]
-            .parse::<f64>()
-            .context("Failed to parse price for levels")?;
-        let amount = arr[1]
-            .parse::<f64>()
-            .context("Failed to parse amount for levels")?;
+    pub fn make_level(arr: &[String; 2]) -> Result<PriceLevel> {
+        let price = parse_decimal(&arr[0]).context("Failed to parse price for levels")?;
+        let amount = parse_decimal(&arr[1]).context("Failed to parse amount for levels")?;
 
-        Ok(Level {
-            exchange: exchange.to_string(),
-            price,
-            amount,
-        })
+        Ok(PriceLevel { price, amount })
     }
 }
@@ -1,53 +1,271 @@
-use anyhow::Result;
-use futures_util::StreamExt;
-use tokio::sync::broadcast::Sender;
-use tokio::time::Duration;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use anyhow::{bail, Context, Result};
+use std::collections::VecDeque;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
 
-use super::Orderbook;
-use crate::orderbook::Summary;
+use super::book::{LocalBook, Side};
+use super::{covering, Book, Exchange, ExchangeConfig, Orderbook};
+
+const URL: &str = "wss://stream.binance.com:9443";
+const REST_URL: &str = "https://api.binance.com";
+
+// Levels requested in the REST snapshot of the diff mode
+const SNAPSHOT_LIMIT: usize = 1000;
+
+// Diff updates kept while the snapshot is fetched, the oldest ones are dropped beyond it
+const BUFFER_LIMIT: usize = 1000;
+
+// Levels available on the partial depth stream
+const PARTIAL_DEPTHS: [usize; 3] = [5, 10, 20];
 
 #[derive(Debug)]
-pub struct BinanceExchange {}
+pub struct BinanceExchange {
+    url: String,
+    // Full book from the diff stream, None for the partial depth stream
+    diff: Option<DiffBook>,
+}
 
 impl BinanceExchange {
-    // One process to fetch Binance exchange order books and push to channel
-    pub async fn start(symbol: String, url: String, sender: Sender<Summary>) -> Result<()> {
-        let url = url + "/ws/" + &symbol + "@depth10@100ms";
-
-        let sender_copy = sender.clone();
-
-        tokio::spawn(async move {
-            loop {
-                // connect to Binance websocket
-                let (_, mut ws_read) = match connect_async(&url).await {
-                    Ok((stream, _)) => stream.split(),
-                    Err(err) => {
-                        eprintln!("Binance connection failure: {err}");
-                        tokio::time::sleep(Duration::from_secs(5)).await;
-                        continue;
-                    }
-                };
+    pub fn build(config: &ExchangeConfig, symbol: &str) -> Result<Box<dyn Exchange>> {
+        let diff = match config.option("mode").unwrap_or("partial") {
+            "partial" => None,
+            "diff" => Some(DiffBook::new(
+                format!(
+                    "{}/api/v3/depth?symbol={}&limit={SNAPSHOT_LIMIT}",
+                    config.option("rest_url").unwrap_or(REST_URL),
+                    symbol.to_uppercase()
+                ),
+                config.snapshot_client()?,
+            )),
+            mode => bail!("Unknown Binance mode: {mode}"),
+        };
+        let url = config.url(URL) + "/ws/" + symbol;
+
+        Ok(Box::new(Self { url, diff }))
+    }
+}
+
+#[tonic::async_trait]
+impl Exchange for BinanceExchange {
+    fn name(&self) -> &str {
+        "BINANCE"
+    }
+
+    fn url(&self, depth: usize) -> String {
+        match self.diff {
+            None => format!("{}@depth{}@100ms", self.url, self.subscribed_depth(depth)),
+            Some(_) => format!("{}@depth@100ms", self.url),
+        }
+    }
+
+    // Binance streams are selected by url, nothing to send
+    fn subscribe(&mut self, _: usize) -> Vec<Message> {
+        if let Some(diff) = &mut self.diff {
+            diff.reset();
+        }
+        Vec::new()
+    }
 
-                // Listen to messages
-                while let Some(msg) = ws_read.next().await {
-                    let Ok(Message::Text(text)) = msg else { continue };
-                    let Ok(data) = serde_json::from_str::<Orderbook>(&text) else { continue };
-                    match data.convert("BINANCE") {
-                        Ok(summary) => {
-                            // send the orderbook to channel
-                            _ = sender_copy.send(summary);
-                        }
-                        Err(err) => {
-                            eprintln!("Binance message parse failure: {err}");
-                        }
-                    }
+    // Smallest partial depth stream covering `depth`, the diff stream always has the full book
+    fn subscribed_depth(&self, depth: usize) -> usize {
+        match self.diff {
+            None => covering(&PARTIAL_DEPTHS, depth),
+            Some(_) => usize::MAX,
+        }
+    }
+
+    async fn parse(&mut self, msg: Message, depth: usize) -> Result<Option<Book>> {
+        let Message::Text(text) = msg else {
+            return Ok(None);
+        };
+        match &mut self.diff {
+            None => match serde_json::from_str::<Snapshot>(&text) {
+                Ok(snapshot) => {
+                    let mut book = snapshot.book.convert(depth)?;
+                    book.sequence = Some(snapshot.last_update_id);
+                    Ok(Some(book))
                 }
+                Err(_) => Ok(None),
+            },
+            Some(diff) => {
+                let Ok(update) = serde_json::from_str::<DepthUpdate>(&text) else {
+                    return Ok(None);
+                };
+                diff.apply(update, depth).await
             }
+        }
+    }
+}
+
+// Local book kept in sync with the diff depth stream, following
+// https://binance-docs.github.io/apidocs/spot/en/#how-to-manage-a-local-order-book-correctly.
+// Updates are buffered while the REST snapshot is fetched in the background
+#[derive(Debug)]
+struct DiffBook {
+    snapshot_url: String,
+    client: reqwest::Client,
+    book: LocalBook,
+    // Last update applied to the book, None until synced with a snapshot
+    last_update_id: Option<u64>,
+    // Event time of the last update applied, in microseconds
+    event_time: Option<u64>,
+    // Synced with a snapshot, the next update may start before it
+    overlap: bool,
+    // Updates received until the snapshot is fetched
+    buffer: VecDeque<DepthUpdate>,
+    snapshot: Option<JoinHandle<Result<Snapshot>>>,
+}
+
+impl DiffBook {
+    fn new(snapshot_url: String, client: reqwest::Client) -> Self {
+        Self {
+            snapshot_url,
+            client,
+            book: LocalBook::default(),
+            last_update_id: None,
+            event_time: None,
+            overlap: false,
+            buffer: VecDeque::new(),
+            snapshot: None,
+        }
+    }
+
+    // Forget the book until the next snapshot
+    fn reset(&mut self) {
+        self.last_update_id = None;
+        self.buffer.clear();
+        if let Some(snapshot) = self.snapshot.take() {
+            snapshot.abort();
+        }
+    }
+
+    async fn apply(&mut self, update: DepthUpdate, depth: usize) -> Result<Option<Book>> {
+        let Some(id) = self.last_update_id else {
+            if self.buffer.len() == BUFFER_LIMIT {
+                self.buffer.pop_front();
+            }
+            self.buffer.push_back(update);
+            return self.sync(depth).await;
+        };
+
+        if !self.follows(&update) {
+            let first = update.first_update_id;
+            self.reset();
+            self.buffer.push_back(update);
+            bail!("Binance update {first} does not follow {id}, resyncing");
+        }
+        self.update(&update)?;
+        Ok(Some(self.book(depth)))
+    }
+
+    // Apply the snapshot once fetched and the buffered updates following it, None until then
+    async fn sync(&mut self, depth: usize) -> Result<Option<Book>> {
+        let fetch = self.snapshot.get_or_insert_with(|| {
+            tokio::spawn(fetch(self.client.clone(), self.snapshot_url.clone()))
         });
+        if !fetch.is_finished() {
+            return Ok(None);
+        }
+        let snapshot = fetch.await.context("Binance snapshot task failed");
+        self.snapshot = None;
+        let snapshot = snapshot??;
+
+        // Updates up to the snapshot are already in it
+        let id = snapshot.last_update_id;
+        self.buffer.retain(|update| update.final_update_id > id);
+        if let Some(update) = self.buffer.front() {
+            if update.first_update_id > id + 1 {
+                bail!(
+                    "Binance snapshot {id} is older than update {}, fetching a new one",
+                    update.first_update_id
+                );
+            }
+        }
 
-        println!("Binance connected");
+        self.book.reset(&snapshot.book)?;
+        self.last_update_id = Some(id);
+        self.event_time = None;
+        self.overlap = true;
+        for update in std::mem::take(&mut self.buffer) {
+            if !self.follows(&update) {
+                let last = self.last_update_id.unwrap_or_default();
+                self.reset();
+                bail!(
+                    "Binance update {} does not follow {last}, resyncing",
+                    update.first_update_id
+                );
+            }
+            self.update(&update)?;
+        }
+        Ok(Some(self.book(depth)))
+    }
 
+    // The first update after the snapshot covers the update following it, the next ones
+    // start right after the previous one
+    fn follows(&self, update: &DepthUpdate) -> bool {
+        match self.last_update_id {
+            Some(id) if self.overlap => {
+                update.first_update_id <= id + 1 && id < update.final_update_id
+            }
+            Some(id) => update.first_update_id == id + 1,
+            None => false,
+        }
+    }
+
+    fn update(&mut self, update: &DepthUpdate) -> Result<()> {
+        for level in &update.bids {
+            self.book.update(Side::Bid, level)?;
+        }
+        for level in &update.asks {
+            self.book.update(Side::Ask, level)?;
+        }
+        self.last_update_id = Some(update.final_update_id);
+        self.event_time = update.event_time.map(|time| time * 1000);
+        self.overlap = false;
         Ok(())
     }
+
+    fn book(&self, depth: usize) -> Book {
+        let mut book = self.book.book(depth);
+        book.event_time = self.event_time;
+        book.sequence = self.last_update_id;
+        book
+    }
+}
+
+// Fetch the REST snapshot
+async fn fetch(client: reqwest::Client, url: String) -> Result<Snapshot> {
+    client
+        .get(&url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .context("Failed to fetch Binance snapshot")?
+        .json::<Snapshot>()
+        .await
+        .context("Failed to parse Binance snapshot")
+}
+
+// REST snapshot, also the payload of the partial depth stream
+#[derive(Debug, serde::Deserialize)]
+struct Snapshot {
+    #[serde(rename = "lastUpdateId")]
+    last_update_id: u64,
+    #[serde(flatten)]
+    book: Orderbook,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DepthUpdate {
+    // Event time in milliseconds
+    #[serde(rename = "E")]
+    event_time: Option<u64>,
+    #[serde(rename = "U")]
+    first_update_id: u64,
+    #[serde(rename = "u")]
+    final_update_id: u64,
+    #[serde(rename = "b")]
+    bids: Vec<[String; 2]>,
+    #[serde(rename = "a")]
+    asks: Vec<[String; 2]>,
 }
@@ -0,0 +1,231 @@
+use anyhow::{bail, Context, Result};
+use rust_decimal::Decimal;
+use serde_json::{json, Value};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::book::{LocalBook, Side};
+use super::{covering, parse_decimal, symbol, Book, Exchange, ExchangeConfig, PriceLevel};
+
+const URL: &str = "wss://api-pub.bitfinex.com/ws/2";
+
+// Book lengths available on the book channel
+const LENGTHS: [usize; 4] = [1, 25, 100, 250];
+
+// Price aggregation levels of the book channel, P0 has the most significant figures
+const PRECISIONS: [&str; 5] = ["P0", "P1", "P2", "P3", "P4"];
+
+// `conf` flag adding a checksum message after every book update
+const CHECKSUM_FLAG: u64 = 131072;
+
+// Levels per side covered by the book checksum
+const CHECKSUM_DEPTH: usize = 25;
+
+#[derive(Debug)]
+pub struct BitfinexExchange {
+    url: String,
+    // Symbol as `tBASEQUOTE`
+    symbol: String,
+    precision: String,
+    // Length of the book subscription
+    length: usize,
+    // Id of the book channel, None until subscribed
+    chan_id: Option<u64>,
+    book: LocalBook,
+    // Snapshot received on the current subscription
+    synced: bool,
+    replies: Vec<Message>,
+}
+
+impl BitfinexExchange {
+    pub fn build(config: &ExchangeConfig, symbol: &str) -> Result<Box<dyn Exchange>> {
+        let symbol = match symbol::option(config, symbol) {
+            Some(symbol) => symbol.to_string(),
+            None => {
+                let (base, quote) = symbol::currencies(symbol)?;
+                // Currencies longer than 3 letters are separated by a colon
+                if base.len() == 3 && quote.len() == 3 {
+                    format!("t{base}{quote}")
+                } else {
+                    format!("t{base}:{quote}")
+                }
+            }
+        };
+        let precision = config.option("precision").unwrap_or("P0").to_uppercase();
+        if !PRECISIONS.contains(&precision.as_str()) {
+            bail!("Unknown Bitfinex precision: {precision}");
+        }
+
+        Ok(Box::new(Self {
+            url: config.url(URL),
+            symbol,
+            precision,
+            length: LENGTHS[1],
+            chan_id: None,
+            book: LocalBook::default(),
+            synced: false,
+            replies: Vec::new(),
+        }))
+    }
+
+    fn subscription(&self) -> Message {
+        let msg = json!({
+            "event": "subscribe",
+            "channel": "book",
+            "symbol": self.symbol,
+            "prec": self.precision,
+            "freq": "F0",
+            "len": self.length.to_string(),
+        });
+        Message::Text(msg.to_string())
+    }
+
+    // Subscribe again for a new snapshot
+    fn resync(&mut self) {
+        self.synced = false;
+        if let Some(chan_id) = self.chan_id.take() {
+            let msg = json!({"event": "unsubscribe", "chanId": chan_id});
+            self.replies = vec![Message::Text(msg.to_string()), self.subscription()];
+        }
+    }
+
+    fn event(&mut self, val: &Value) -> Result<()> {
+        match val["event"].as_str() {
+            Some("subscribed") if val["symbol"] == self.symbol.as_str() => {
+                self.chan_id = val["chanId"].as_u64();
+                self.synced = false;
+            }
+            Some("error") => bail!("Bitfinex error {}: {}", val["code"], val["msg"]),
+            Some("conf") if val["status"] != "OK" => {
+                bail!("Bitfinex checksums not enabled: {val}")
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Apply `[price, count, amount]`, zero count removes the level and asks have negative amounts
+    fn apply(&mut self, level: &Value) -> Result<()> {
+        let field = |index: usize| match &level[index] {
+            Value::Number(value) => parse_decimal(&value.to_string()),
+            value => bail!("Invalid Bitfinex level field {value}"),
+        };
+        let (price, count, amount) = (field(0)?, field(1)?, field(2)?);
+        let side = if amount.is_sign_negative() {
+            Side::Ask
+        } else {
+            Side::Bid
+        };
+        let amount = if count.is_zero() {
+            Decimal::ZERO
+        } else {
+            amount.abs()
+        };
+        self.book.set(side, PriceLevel { price, amount });
+        Ok(())
+    }
+
+    // CRC32 of the top bids and asks interleaved as `bid:amount:ask:-amount:...`, signed
+    fn checksum(&self) -> i32 {
+        let book = self.book.book(CHECKSUM_DEPTH);
+        let mut fields = Vec::new();
+        for index in 0..CHECKSUM_DEPTH {
+            if let Some(bid) = book.bids.get(index) {
+                fields.push(bid.price.normalize().to_string());
+                fields.push(bid.amount.normalize().to_string());
+            }
+            if let Some(ask) = book.asks.get(index) {
+                fields.push(ask.price.normalize().to_string());
+                fields.push((-ask.amount).normalize().to_string());
+            }
+        }
+        crc32fast::hash(fields.join(":").as_bytes()) as i32
+    }
+}
+
+#[tonic::async_trait]
+impl Exchange for BitfinexExchange {
+    fn name(&self) -> &str {
+        "BITFINEX"
+    }
+
+    fn url(&self, _: usize) -> String {
+        self.url.clone()
+    }
+
+    fn subscribe(&mut self, depth: usize) -> Vec<Message> {
+        self.length = self.subscribed_depth(depth);
+        self.chan_id = None;
+        self.synced = false;
+        self.book.clear();
+        self.replies.clear();
+
+        let conf = json!({"event": "conf", "flags": CHECKSUM_FLAG});
+        vec![Message::Text(conf.to_string()), self.subscription()]
+    }
+
+    // Smallest book length covering `depth`
+    fn subscribed_depth(&self, depth: usize) -> usize {
+        covering(&LENGTHS, depth)
+    }
+
+    // Events are objects, channel messages are `[chan_id, payload, ...]` arrays
+    async fn parse(&mut self, msg: Message, depth: usize) -> Result<Option<Book>> {
+        let Message::Text(text) = msg else {
+            return Ok(None);
+        };
+        let Ok(val) = serde_json::from_str::<Value>(&text) else {
+            return Ok(None);
+        };
+        if val.is_object() {
+            self.event(&val)?;
+            return Ok(None);
+        }
+        if self.chan_id.is_none() || val[0].as_u64() != self.chan_id {
+            return Ok(None);
+        }
+
+        match &val[1] {
+            // Heartbeat
+            Value::String(kind) if kind == "hb" => return Ok(None),
+            Value::String(kind) if kind == "cs" => {
+                if !self.synced {
+                    return Ok(None);
+                }
+                let expected = val[2].as_i64().context("Invalid Bitfinex checksum")? as i32;
+                let checksum = self.checksum();
+                if checksum != expected {
+                    self.resync();
+                    bail!(
+                        "Bitfinex checksum mismatch for {}: expected {expected}, got {checksum}, resubscribing",
+                        self.symbol
+                    );
+                }
+                return Ok(None);
+            }
+            // Snapshot as an array of levels, empty for an empty book
+            Value::Array(levels) if levels.first().is_none_or(Value::is_array) => {
+                self.book.clear();
+                for level in levels {
+                    self.apply(level)?;
+                }
+                self.synced = true;
+            }
+            Value::Array(_) if self.synced => {
+                self.apply(&val[1])?;
+                // Levels pushed out of the book length are dropped
+                self.book.truncate(self.length);
+            }
+            _ => return Ok(None),
+        }
+
+        Ok(Some(self.book.book(depth)))
+    }
+
+    fn replies(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.replies)
+    }
+
+    fn keepalive(&mut self) -> Message {
+        Message::Text(json!({"event": "ping"}).to_string())
+    }
+}
@@ -1,70 +1,209 @@
-use anyhow::Result;
-use futures_util::{SinkExt, StreamExt};
-use tokio::sync::broadcast::Sender;
-use tokio::time::Duration;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use anyhow::{bail, Context, Result};
+use std::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::Message;
 
-use super::Orderbook;
-use crate::orderbook::Summary;
+use super::book::{LocalBook, Side};
+use super::{Book, Exchange, ExchangeConfig, Orderbook};
+
+const URL: &str = "wss://ws.bitstamp.net";
+const REST_URL: &str = "https://www.bitstamp.net";
+
+// Time between the REST snapshots of the diff mode
+const DEFAULT_RESYNC_INTERVAL_MS: u64 = 60_000;
+
+// Levels on the order book channel
+const PARTIAL_DEPTH: usize = 100;
 
 #[derive(Debug)]
-pub struct BitstampExchange {}
+pub struct BitstampExchange {
+    url: String,
+    subscription: String,
+    // Full book from the diff channel, None for the top 100 channel
+    diff: Option<DiffBook>,
+}
 
 impl BitstampExchange {
-    // One process to fetch Bitstamp exchange order books and push to channel
-    pub async fn start(symbol: String, url: String, sender: Sender<Summary>) -> Result<()> {
-        let subscription = r#"{"event":"bts:subscribe","data":{"channel":"order_book_"#.to_string()
-            + &symbol
+    pub fn build(config: &ExchangeConfig, symbol: &str) -> Result<Box<dyn Exchange>> {
+        let (channel, diff) = match config.option("mode").unwrap_or("partial") {
+            "partial" => ("order_book_", None),
+            "diff" => (
+                "diff_order_book_",
+                Some(DiffBook {
+                    snapshot_url: format!(
+                        "{}/api/v2/order_book/{symbol}/",
+                        config.option("rest_url").unwrap_or(REST_URL)
+                    ),
+                    client: config.snapshot_client()?,
+                    book: LocalBook::default(),
+                    microtimestamp: None,
+                    resync_interval: resync_interval(config)?,
+                    synced_at: Instant::now(),
+                }),
+            ),
+            mode => bail!("Unknown Bitstamp mode: {mode}"),
+        };
+        let subscription = r#"{"event":"bts:subscribe","data":{"channel":""#.to_string()
+            + channel
+            + symbol
             + r#""}}"#;
 
-        let sender_copy = sender.clone();
-
-        tokio::spawn(async move {
-            loop {
-                // connect to Bitstamp websocket
-                let (mut ws_write, mut ws_read) = match connect_async(&url).await {
-                    Ok((stream, _)) => stream.split(),
-                    Err(err) => {
-                        eprintln!("Bitstamp connection failure: {err}");
-                        tokio::time::sleep(Duration::from_secs(5)).await;
-                        continue;
-                    }
-                };
-
-                // Send subscription message on the websocket
-                if let Err(err) = ws_write.send(Message::Text(subscription.clone())).await {
-                    eprintln!("Bitstamp subscription failure: {err}");
-                    continue;
-                }
-
-                // Listen to messages
-                while let Some(msg) = ws_read.next().await {
-                    let Ok(Message::Text(text)) = msg else { continue };
-                    let Ok(val) = serde_json::from_str::<serde_json::Value>(&text) else { continue };
-                    if val["event"] != "data" {
-                        continue;
-                    }
-                    let Ok(data) = serde_json::from_value::<Data>(val) else { continue };
-                    match data.data.convert("BITSTAMP") {
-                        Ok(summary) => {
-                            // send the orderbook to channel
-                            _ = sender_copy.send(summary);
-                        }
-                        Err(err) => {
-                            eprintln!("Bitstamp message parse failure: {err}");
-                        }
-                    }
-                }
-            }
-        });
-
-        println!("Bitstamp connected");
-
-        Ok(())
+        Ok(Box::new(Self {
+            url: config.url(URL),
+            subscription,
+            diff,
+        }))
+    }
+}
+
+// Zero disables the periodic resync
+fn resync_interval(config: &ExchangeConfig) -> Result<Option<Duration>> {
+    let millis = match config.option("resync_interval_ms") {
+        Some(value) => value
+            .parse()
+            .with_context(|| format!("Invalid resync_interval_ms '{value}'"))?,
+        None => DEFAULT_RESYNC_INTERVAL_MS,
+    };
+    Ok((millis > 0).then(|| Duration::from_millis(millis)))
+}
+
+#[tonic::async_trait]
+impl Exchange for BitstampExchange {
+    fn name(&self) -> &str {
+        "BITSTAMP"
+    }
+
+    fn url(&self, _: usize) -> String {
+        self.url.clone()
+    }
+
+    fn subscribe(&mut self, _: usize) -> Vec<Message> {
+        if let Some(diff) = &mut self.diff {
+            diff.microtimestamp = None;
+        }
+        vec![Message::Text(self.subscription.clone())]
+    }
+
+    // The order book channel always has the top 100 levels, the diff channel the full book
+    fn subscribed_depth(&self, _: usize) -> usize {
+        match self.diff {
+            None => PARTIAL_DEPTH,
+            Some(_) => usize::MAX,
+        }
+    }
+
+    async fn parse(&mut self, msg: Message, depth: usize) -> Result<Option<Book>> {
+        let Message::Text(text) = msg else {
+            return Ok(None);
+        };
+        let Ok(val) = serde_json::from_str::<serde_json::Value>(&text) else {
+            return Ok(None);
+        };
+        if val["event"] != "data" {
+            return Ok(None);
+        }
+        let Ok(data) = serde_json::from_value::<Data>(val) else {
+            return Ok(None);
+        };
+        let microtimestamp = data.data.microtimestamp().ok();
+        let mut book = match &mut self.diff {
+            None => data.data.book.convert(depth)?,
+            Some(diff) => match diff.apply(data.data, depth).await? {
+                Some(book) => book,
+                None => return Ok(None),
+            },
+        };
+        book.event_time = microtimestamp;
+        Ok(Some(book))
+    }
+}
+
+// Local book kept in sync with the diff channel, updates are ordered by their microtimestamp.
+// The channel has no sequence numbers, a missed update is only detected when it leaves the book
+// crossed, so the book is also reset from a new snapshot every resync interval
+#[derive(Debug)]
+struct DiffBook {
+    snapshot_url: String,
+    client: reqwest::Client,
+    book: LocalBook,
+    // Microtimestamp of the last update applied to the book, None until synced with a snapshot
+    microtimestamp: Option<u64>,
+    resync_interval: Option<Duration>,
+    synced_at: Instant,
+}
+
+impl DiffBook {
+    async fn apply(&mut self, update: Update, depth: usize) -> Result<Option<Book>> {
+        let microtimestamp = update.microtimestamp()?;
+        let expired = self
+            .resync_interval
+            .is_some_and(|interval| self.synced_at.elapsed() >= interval);
+        let last = match self.microtimestamp {
+            Some(last) if !expired => last,
+            _ => self.sync().await?,
+        };
+        // Updates up to the snapshot are already in the book
+        if microtimestamp <= last {
+            return Ok(None);
+        }
+
+        for level in &update.book.bids {
+            self.book.update(Side::Bid, level)?;
+        }
+        for level in &update.book.asks {
+            self.book.update(Side::Ask, level)?;
+        }
+        self.microtimestamp = Some(microtimestamp);
+
+        // A crossed book means updates were missed, start over from a snapshot
+        if self.book.is_crossed() {
+            self.microtimestamp = None;
+            bail!("Bitstamp book crossed at {microtimestamp}, resyncing");
+        }
+
+        Ok(Some(self.book.book(depth)))
+    }
+
+    // Reset the book from the REST snapshot and return its microtimestamp
+    async fn sync(&mut self) -> Result<u64> {
+        self.microtimestamp = None;
+
+        let snapshot = self
+            .client
+            .get(&self.snapshot_url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .context("Failed to fetch Bitstamp snapshot")?
+            .json::<Update>()
+            .await
+            .context("Failed to parse Bitstamp snapshot")?;
+
+        let microtimestamp = snapshot.microtimestamp()?;
+        self.book.reset(&snapshot.book)?;
+        self.microtimestamp = Some(microtimestamp);
+        self.synced_at = Instant::now();
+
+        Ok(microtimestamp)
     }
 }
 
 #[derive(Debug, serde::Deserialize)]
 struct Data {
-    pub data: Orderbook,
+    pub data: Update,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Update {
+    #[serde(default)]
+    microtimestamp: String,
+    #[serde(flatten)]
+    book: Orderbook,
+}
+
+impl Update {
+    fn microtimestamp(&self) -> Result<u64> {
+        self.microtimestamp
+            .parse()
+            .context("Failed to parse Bitstamp microtimestamp")
+    }
 }
@@ -0,0 +1,89 @@
+use anyhow::Result;
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+
+use super::{Book, Orderbook, PriceLevel};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Bid,
+    Ask,
+}
+
+// Full depth order book maintained from exchange snapshots and diffs
+#[derive(Debug, Default)]
+pub struct LocalBook {
+    bids: BTreeMap<Decimal, Decimal>,
+    asks: BTreeMap<Decimal, Decimal>,
+}
+
+impl LocalBook {
+    pub fn clear(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+    }
+
+    // Replace the book with a snapshot
+    pub fn reset(&mut self, snapshot: &Orderbook) -> Result<()> {
+        self.clear();
+        self.apply(snapshot)
+    }
+
+    // Apply the levels of an update to both sides
+    pub fn apply(&mut self, update: &Orderbook) -> Result<()> {
+        for level in &update.bids {
+            self.update(Side::Bid, level)?;
+        }
+        for level in &update.asks {
+            self.update(Side::Ask, level)?;
+        }
+        Ok(())
+    }
+
+    // Set the amount of a price level, zero amount removes the level
+    pub fn update(&mut self, side: Side, level: &[String; 2]) -> Result<()> {
+        let level = Orderbook::make_level(level)?;
+        self.set(side, level);
+        Ok(())
+    }
+
+    pub fn set(&mut self, side: Side, level: PriceLevel) {
+        let levels = match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        };
+        if level.amount.is_zero() {
+            levels.remove(&level.price);
+        } else {
+            levels.insert(level.price, level.amount);
+        }
+    }
+
+    // Keep the best `depth` levels of both sides
+    pub fn truncate(&mut self, depth: usize) {
+        while self.bids.len() > depth {
+            self.bids.pop_first();
+        }
+        while self.asks.len() > depth {
+            self.asks.pop_last();
+        }
+    }
+
+    // Best bid at or above the best ask, only happens when updates were missed
+    pub fn is_crossed(&self) -> bool {
+        match (self.bids.keys().next_back(), self.asks.keys().next()) {
+            (Some(bid), Some(ask)) => bid >= ask,
+            _ => false,
+        }
+    }
+
+    // Best levels of both sides, bids descending and asks ascending
+    pub fn book(&self, depth: usize) -> Book {
+        let level = |(&price, &amount)| PriceLevel { price, amount };
+        Book {
+            bids: self.bids.iter().rev().take(depth).map(level).collect(),
+            asks: self.asks.iter().take(depth).map(level).collect(),
+            ..<_>::default()
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::warn;
+
+use super::book::LocalBook;
+use super::{covering, deserialize_levels, symbol, Book, Exchange, ExchangeConfig, Orderbook};
+
+const URL: &str = "wss://stream.bybit.com/v5/public/spot";
+
+// Depths available on the spot orderbook topic
+const DEPTHS: [usize; 2] = [50, 200];
+
+#[derive(Debug)]
+pub struct BybitExchange {
+    url: String,
+    // Uppercase symbol as `BASEQUOTE`
+    symbol: String,
+    // Topic of the current subscription
+    topic: String,
+    book: LocalBook,
+    // Update id of the last message applied to the book, None until the snapshot
+    update_id: Option<u64>,
+    // Snapshots received in the middle of the stream, since the last call to `resyncs`
+    resyncs: u64,
+    replies: Vec<Message>,
+}
+
+impl BybitExchange {
+    pub fn build(config: &ExchangeConfig, symbol: &str) -> Result<Box<dyn Exchange>> {
+        let symbol = symbol::format(config, symbol, "")?;
+
+        Ok(Box::new(Self {
+            url: config.url(URL),
+            symbol,
+            topic: String::new(),
+            book: LocalBook::default(),
+            update_id: None,
+            resyncs: 0,
+            replies: Vec::new(),
+        }))
+    }
+
+    fn request(&self, op: &str) -> Message {
+        Message::Text(json!({"op": op, "args": [self.topic]}).to_string())
+    }
+
+    fn apply(&mut self, msg: Update, depth: usize) -> Result<Option<Book>> {
+        let levels = Orderbook {
+            bids: msg.data.bids,
+            asks: msg.data.asks,
+        };
+        match (msg.kind.as_str(), self.update_id) {
+            // A snapshot in the middle of the stream, e.g. after a service restart, resyncs the
+            // book and its update ids
+            ("snapshot", Some(id)) => {
+                warn!(
+                    update_id = msg.data.update_id,
+                    previous = id,
+                    "Bybit snapshot in the middle of the stream, resyncing"
+                );
+                self.resyncs += 1;
+                self.book.reset(&levels)?;
+            }
+            ("snapshot", None) => self.book.reset(&levels)?,
+            // Deltas of the previous subscription until the new snapshot comes in
+            (_, None) => return Ok(None),
+            ("delta", Some(id)) if msg.data.update_id == id + 1 => {
+                self.book.apply(&levels)?;
+            }
+            (kind, Some(id)) => {
+                self.update_id = None;
+                self.replies = vec![self.request("unsubscribe"), self.request("subscribe")];
+                bail!(
+                    "Bybit {kind} {} does not follow {id} for {}, resubscribing",
+                    msg.data.update_id,
+                    self.symbol
+                );
+            }
+        }
+        self.update_id = Some(msg.data.update_id);
+
+        let mut book = self.book.book(depth);
+        book.event_time = msg.cts.or(msg.ts).map(|ts| ts * 1000);
+        book.sequence = msg.data.seq;
+        Ok(Some(book))
+    }
+}
+
+#[tonic::async_trait]
+impl Exchange for BybitExchange {
+    fn name(&self) -> &str {
+        "BYBIT"
+    }
+
+    fn url(&self, _: usize) -> String {
+        self.url.clone()
+    }
+
+    fn subscribe(&mut self, depth: usize) -> Vec<Message> {
+        self.topic = format!("orderbook.{}.{}", self.subscribed_depth(depth), self.symbol);
+        self.book.clear();
+        self.update_id = None;
+        self.replies.clear();
+        vec![self.request("subscribe")]
+    }
+
+    // Smallest orderbook depth covering `depth`
+    fn subscribed_depth(&self, depth: usize) -> usize {
+        covering(&DEPTHS, depth)
+    }
+
+    async fn parse(&mut self, msg: Message, depth: usize) -> Result<Option<Book>> {
+        let Message::Text(text) = msg else {
+            return Ok(None);
+        };
+        let Ok(val) = serde_json::from_str::<Value>(&text) else {
+            return Ok(None);
+        };
+        if val["success"] == false {
+            bail!("Bybit {} failure: {}", val["op"], val["ret_msg"]);
+        }
+        if val["topic"] != self.topic.as_str() {
+            return Ok(None);
+        }
+
+        let msg = serde_json::from_value::<Update>(val).context("Failed to parse Bybit book")?;
+        self.apply(msg, depth)
+    }
+
+    fn replies(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.replies)
+    }
+
+    fn resyncs(&mut self) -> u64 {
+        std::mem::take(&mut self.resyncs)
+    }
+
+    // Bybit expects its own ping every 20 seconds
+    fn keepalive(&mut self) -> Message {
+        Message::Text(json!({"op": "ping"}).to_string())
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Update {
+    #[serde(rename = "type")]
+    kind: String,
+    // Times in milliseconds, `cts` is the matching engine time
+    ts: Option<u64>,
+    cts: Option<u64>,
+    data: Data,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Data {
+    #[serde(rename = "b", deserialize_with = "deserialize_levels")]
+    bids: Vec<[String; 2]>,
+    #[serde(rename = "a", deserialize_with = "deserialize_levels")]
+    asks: Vec<[String; 2]>,
+    #[serde(rename = "u")]
+    update_id: u64,
+    // Cross sequence, comparable between depths
+    seq: Option<u64>,
+}
@@ -0,0 +1,131 @@
+use anyhow::{bail, Result};
+use serde_json::json;
+use tokio_tungstenite::tungstenite::Message;
+
+use super::book::{LocalBook, Side};
+use super::{symbol, Book, Exchange, ExchangeConfig};
+
+const URL: &str = "wss://ws-feed.exchange.coinbase.com";
+
+#[derive(Debug)]
+pub struct CoinbaseExchange {
+    url: String,
+    // Product id as `BASE-QUOTE`
+    product: String,
+    book: LocalBook,
+    // Snapshot received on the current connection
+    synced: bool,
+}
+
+impl CoinbaseExchange {
+    pub fn build(config: &ExchangeConfig, symbol: &str) -> Result<Box<dyn Exchange>> {
+        let product = symbol::format(config, symbol, "-")?;
+
+        Ok(Box::new(Self {
+            url: config.url(URL),
+            product,
+            book: LocalBook::default(),
+            synced: false,
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl Exchange for CoinbaseExchange {
+    fn name(&self) -> &str {
+        "COINBASE"
+    }
+
+    fn url(&self, _: usize) -> String {
+        self.url.clone()
+    }
+
+    fn subscribe(&mut self, _: usize) -> Vec<Message> {
+        self.book.clear();
+        self.synced = false;
+
+        let msg = json!({
+            "type": "subscribe",
+            "product_ids": [self.product],
+            "channels": ["level2_batch"],
+        });
+        vec![Message::Text(msg.to_string())]
+    }
+
+    // The level2 channel always has the full book
+    fn subscribed_depth(&self, _: usize) -> usize {
+        usize::MAX
+    }
+
+    async fn parse(&mut self, msg: Message, depth: usize) -> Result<Option<Book>> {
+        let Message::Text(text) = msg else {
+            return Ok(None);
+        };
+        let Ok(msg) = serde_json::from_str::<Level2>(&text) else {
+            return Ok(None);
+        };
+        if msg
+            .product_id
+            .as_deref()
+            .is_some_and(|id| id != self.product)
+        {
+            return Ok(None);
+        }
+
+        match msg.kind.as_str() {
+            "snapshot" => {
+                self.book.clear();
+                for level in &msg.bids {
+                    self.book.update(Side::Bid, level)?;
+                }
+                for level in &msg.asks {
+                    self.book.update(Side::Ask, level)?;
+                }
+                self.synced = true;
+            }
+            "l2update" if self.synced => {
+                for [side, price, size] in msg.changes {
+                    let side = match side.as_str() {
+                        "buy" => Side::Bid,
+                        "sell" => Side::Ask,
+                        _ => bail!("Unknown Coinbase side: {side}"),
+                    };
+                    self.book.update(side, &[price, size])?;
+                }
+            }
+            "error" => bail!(
+                "Coinbase error: {} {}",
+                msg.message.unwrap_or_default(),
+                msg.reason.unwrap_or_default()
+            ),
+            _ => return Ok(None),
+        }
+
+        let mut book = self.book.book(depth);
+        book.event_time = msg
+            .time
+            .as_deref()
+            .and_then(|time| chrono::DateTime::parse_from_rfc3339(time).ok())
+            .map(|time| time.timestamp_micros() as u64);
+        Ok(Some(book))
+    }
+}
+
+// Snapshot, update or error message of the level2 channel
+#[derive(Debug, serde::Deserialize)]
+struct Level2 {
+    #[serde(rename = "type")]
+    kind: String,
+    product_id: Option<String>,
+    time: Option<String>,
+    // Snapshot levels
+    #[serde(default)]
+    bids: Vec<[String; 2]>,
+    #[serde(default)]
+    asks: Vec<[String; 2]>,
+    // Updates as `[side, price, size]`, zero size removes the level
+    #[serde(default)]
+    changes: Vec<[String; 3]>,
+    message: Option<String>,
+    reason: Option<String>,
+}
@@ -0,0 +1,211 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Number, Value};
+use std::collections::HashMap;
+use tokio_tungstenite::tungstenite::Message;
+
+use super::book::{LocalBook, Side};
+use super::{symbol, Book, Exchange, ExchangeConfig};
+
+const URL: &str = "wss://www.deribit.com/ws/api/v2";
+
+// Seconds between the heartbeats of the server
+const HEARTBEAT_INTERVAL: u64 = 10;
+
+// Decimals kept when converting contract amounts to the base currency
+const AMOUNT_DECIMALS: u32 = 8;
+
+#[derive(Debug)]
+pub struct DeribitExchange {
+    url: String,
+    // Instrument name, e.g. `BTC-PERPETUAL` or `ETH_USDC`
+    instrument: String,
+    // Inverse future or perpetual, e.g. `BTC-PERPETUAL`, with amounts in USD
+    inverse: bool,
+    channel: String,
+    book: LocalBook,
+    // Change id of the last notification applied to the book, None until the snapshot
+    change_id: Option<u64>,
+    // Id of the next request
+    next_id: u64,
+    // Method of every request waiting for its response, by request id
+    pending: HashMap<u64, String>,
+    replies: Vec<Message>,
+}
+
+impl DeribitExchange {
+    pub fn build(config: &ExchangeConfig, symbol: &str) -> Result<Box<dyn Exchange>> {
+        // Trade pairs map to spot, futures and perpetuals are set with the symbol option
+        let instrument = match symbol::option(config, symbol) {
+            Some(instrument) => instrument.to_string(),
+            None => {
+                let (base, quote) = symbol::currencies(symbol)?;
+                format!("{base}_{quote}")
+            }
+        };
+        // Linear instruments are `BASE_QUOTE-...`, options have a strike after the expiry
+        let inverse = !instrument.contains('_') && instrument.matches('-').count() == 1;
+
+        Ok(Box::new(Self {
+            url: config.url(URL),
+            channel: format!("book.{instrument}.100ms"),
+            inverse,
+            instrument,
+            book: LocalBook::default(),
+            change_id: None,
+            next_id: 1,
+            pending: HashMap::new(),
+            replies: Vec::new(),
+        }))
+    }
+
+    // JSON-RPC request, remembered until its response comes in
+    fn request(&mut self, method: &str, params: Value) -> Message {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(id, method.to_string());
+
+        let msg = json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params});
+        Message::Text(msg.to_string())
+    }
+
+    fn subscription(&mut self, method: &str) -> Message {
+        let params = json!({"channels": [self.channel]});
+        self.request(method, params)
+    }
+
+    fn response(&mut self, val: &Value) -> Result<()> {
+        let method = val["id"]
+            .as_u64()
+            .and_then(|id| self.pending.remove(&id))
+            .unwrap_or_default();
+        if !val["error"].is_null() {
+            bail!(
+                "Deribit {method} error {}: {}",
+                val["error"]["code"],
+                val["error"]["message"]
+            );
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, data: BookData, depth: usize) -> Result<Option<Book>> {
+        match (data.kind.as_str(), self.change_id) {
+            ("snapshot", _) => self.book.clear(),
+            // Changes of the previous subscription until the new snapshot comes in
+            (_, None) => return Ok(None),
+            (_, Some(id)) if data.prev_change_id == Some(id) => {}
+            (_, Some(id)) => {
+                self.change_id = None;
+                self.replies = vec![
+                    self.subscription("public/unsubscribe"),
+                    self.subscription("public/subscribe"),
+                ];
+                bail!(
+                    "Deribit change {:?} does not follow {id} for {}, resubscribing",
+                    data.prev_change_id,
+                    self.instrument
+                );
+            }
+        }
+
+        // Levels are `[action, price, amount]`, a delete action removes the level
+        for (side, levels) in [(Side::Bid, &data.bids), (Side::Ask, &data.asks)] {
+            for (action, price, amount) in levels {
+                let amount = match action.as_str() {
+                    "delete" => "0".to_string(),
+                    _ => amount.to_string(),
+                };
+                self.book.update(side, &[price.to_string(), amount])?;
+            }
+        }
+        self.change_id = Some(data.change_id);
+
+        let mut book = self.book.book(depth);
+        // Amounts in the base currency like the other exchanges
+        if self.inverse {
+            for level in book.bids.iter_mut().chain(&mut book.asks) {
+                if let Some(amount) = level.amount.checked_div(level.price) {
+                    level.amount = amount.round_dp(AMOUNT_DECIMALS);
+                }
+            }
+        }
+        book.event_time = Some(data.timestamp * 1000);
+        book.sequence = Some(data.change_id);
+        Ok(Some(book))
+    }
+}
+
+#[tonic::async_trait]
+impl Exchange for DeribitExchange {
+    fn name(&self) -> &str {
+        "DERIBIT"
+    }
+
+    fn url(&self, _: usize) -> String {
+        self.url.clone()
+    }
+
+    fn subscribe(&mut self, _: usize) -> Vec<Message> {
+        self.book.clear();
+        self.change_id = None;
+        self.pending.clear();
+        self.replies.clear();
+
+        vec![
+            self.request(
+                "public/set_heartbeat",
+                json!({"interval": HEARTBEAT_INTERVAL}),
+            ),
+            self.subscription("public/subscribe"),
+        ]
+    }
+
+    // The book channel always has the full book
+    fn subscribed_depth(&self, _: usize) -> usize {
+        usize::MAX
+    }
+
+    async fn parse(&mut self, msg: Message, depth: usize) -> Result<Option<Book>> {
+        let Message::Text(text) = msg else {
+            return Ok(None);
+        };
+        let Ok(val) = serde_json::from_str::<Value>(&text) else {
+            return Ok(None);
+        };
+        if !val["id"].is_null() {
+            self.response(&val)?;
+            return Ok(None);
+        }
+
+        match val["method"].as_str() {
+            // The connection is closed unless test requests are answered
+            Some("heartbeat") if val["params"]["type"] == "test_request" => {
+                self.replies = vec![self.request("public/test", json!({}))];
+                Ok(None)
+            }
+            Some("subscription") if val["params"]["channel"] == self.channel.as_str() => {
+                let data = serde_json::from_value::<BookData>(val["params"]["data"].clone())
+                    .context("Failed to parse Deribit book")?;
+                self.apply(data, depth)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn replies(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.replies)
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BookData {
+    #[serde(rename = "type")]
+    kind: String,
+    // Milliseconds
+    timestamp: u64,
+    change_id: u64,
+    prev_change_id: Option<u64>,
+    // Changes as `[action, price, amount]`
+    bids: Vec<(String, Number, Number)>,
+    asks: Vec<(String, Number, Number)>,
+}
@@ -0,0 +1,275 @@
+use anyhow::{bail, Context, Result};
+use rust_decimal::Decimal;
+use serde_json::Value;
+use std::collections::HashMap;
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{parse_decimal, symbol, Book, Exchange, ExchangeConfig, PriceLevel};
+
+// Exchange described by a JSON config file given with the `config` option, for venues
+// streaming the top of their book as plain JSON messages
+#[derive(Debug)]
+pub struct GenericExchange {
+    name: String,
+    url: String,
+    // Subscription messages with the symbol filled in
+    subscribe: Vec<Message>,
+    venue: Venue,
+    replies: Vec<Message>,
+}
+
+impl GenericExchange {
+    pub fn build(config: &ExchangeConfig, symbol: &str) -> Result<Box<dyn Exchange>> {
+        let path = config
+            .option("config")
+            .context("Missing config option for the generic exchange")?;
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read exchange config {path}"))?;
+        let mut venue = serde_json::from_str::<Venue>(&text)
+            .with_context(|| format!("Invalid exchange config {path}"))?;
+
+        // Symbols set in the config are used as they are
+        let lowercase = venue.symbol.lowercase && symbol::option(config, symbol).is_none();
+        let mut symbol = symbol::format(config, symbol, &venue.symbol.separator)?;
+        if lowercase {
+            symbol = symbol.to_lowercase();
+        }
+        let subscribe = venue
+            .subscribe
+            .iter()
+            .map(|template| Message::Text(fill(template, &symbol).to_string()))
+            .collect();
+        // Filters may refer to the symbol too, e.g. the channel of the book messages
+        for value in venue.filter.values_mut() {
+            *value = fill(value, &symbol);
+        }
+
+        Ok(Box::new(Self {
+            name: venue.name.to_uppercase(),
+            url: config.url(&venue.url),
+            subscribe,
+            venue,
+            replies: Vec::new(),
+        }))
+    }
+
+    fn levels(&self, val: &Value, pointer: &str, depth: usize) -> Result<Vec<PriceLevel>> {
+        let levels = val
+            .pointer(pointer)
+            .and_then(Value::as_array)
+            .with_context(|| format!("Missing {} levels at {pointer}", self.name))?;
+        levels
+            .iter()
+            .take(depth)
+            .map(|level| {
+                let price = self.venue.level.price.decimal(level)?;
+                let amount = self.venue.level.amount.decimal(level)?;
+                Ok(PriceLevel { price, amount })
+            })
+            .collect()
+    }
+}
+
+#[tonic::async_trait]
+impl Exchange for GenericExchange {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn url(&self, _: usize) -> String {
+        self.url.clone()
+    }
+
+    fn subscribe(&mut self, _: usize) -> Vec<Message> {
+        self.replies.clear();
+        self.subscribe.clone()
+    }
+
+    // The subscription does not depend on the depth, so a larger one never reconnects
+    fn subscribed_depth(&self, _: usize) -> usize {
+        self.venue.depth.unwrap_or(usize::MAX)
+    }
+
+    async fn parse(&mut self, msg: Message, depth: usize) -> Result<Option<Book>> {
+        let Message::Text(text) = msg else {
+            return Ok(None);
+        };
+        let Ok(val) = serde_json::from_str::<Value>(&text) else {
+            return Ok(None);
+        };
+        if let Some(ping) = &self.venue.ping {
+            if matches(&val, &ping.filter) {
+                self.replies = vec![Message::Text(ping.reply.to_string())];
+                return Ok(None);
+            }
+        }
+        if !matches(&val, &self.venue.filter) {
+            return Ok(None);
+        }
+
+        let mut book = Book {
+            bids: self.levels(&val, &self.venue.bids, depth)?,
+            asks: self.levels(&val, &self.venue.asks, depth)?,
+            ..Book::default()
+        };
+        book.event_time = self
+            .venue
+            .event_time
+            .as_deref()
+            .and_then(|pointer| integer(val.pointer(pointer)?))
+            .map(|time| time * self.venue.time_unit.micros());
+        book.sequence = self
+            .venue
+            .sequence
+            .as_deref()
+            .and_then(|pointer| integer(val.pointer(pointer)?));
+        Ok(Some(book))
+    }
+
+    fn replies(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.replies)
+    }
+
+    fn keepalive(&mut self) -> Message {
+        match &self.venue.keepalive {
+            Some(msg) => Message::Text(msg.to_string()),
+            None => Message::Ping(Vec::new()),
+        }
+    }
+}
+
+// Replace `{symbol}` in every string of a template
+fn fill(template: &Value, symbol: &str) -> Value {
+    match template {
+        Value::String(text) => Value::String(text.replace("{symbol}", symbol)),
+        Value::Array(values) => values.iter().map(|value| fill(value, symbol)).collect(),
+        Value::Object(fields) => fields
+            .iter()
+            .map(|(key, value)| (key.clone(), fill(value, symbol)))
+            .collect(),
+        value => value.clone(),
+    }
+}
+
+// A message matches when the value at every JSON pointer of the filter equals the expected one
+fn matches(val: &Value, filter: &HashMap<String, Value>) -> bool {
+    filter
+        .iter()
+        .all(|(pointer, expected)| val.pointer(pointer) == Some(expected))
+}
+
+// Integer sent as a number or a string
+fn integer(val: &Value) -> Option<u64> {
+    match val {
+        Value::Number(number) => number.as_u64(),
+        Value::String(text) => text.parse().ok(),
+        _ => None,
+    }
+}
+
+// Content of the exchange config file
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Venue {
+    // Name used to tag levels in the merged order book
+    name: String,
+    url: String,
+    #[serde(default)]
+    symbol: SymbolFormat,
+    // Messages sent after connecting, `{symbol}` is replaced with the exchange symbol
+    #[serde(default)]
+    subscribe: Vec<Value>,
+    // JSON pointers and values identifying the book messages
+    #[serde(default)]
+    filter: HashMap<String, Value>,
+    // JSON pointers to the bid and ask levels of a book message
+    bids: String,
+    asks: String,
+    #[serde(default)]
+    level: LevelLayout,
+    // JSON pointers to the event time and the sequence number of a book message
+    event_time: Option<String>,
+    #[serde(default)]
+    time_unit: TimeUnit,
+    sequence: Option<String>,
+    // Levels in every book message, all the levels of the message when not set
+    depth: Option<usize>,
+    ping: Option<Ping>,
+    // Message sent every ping interval, a websocket ping when not set
+    keepalive: Option<Value>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SymbolFormat {
+    // Separator between the base and quote currencies
+    separator: String,
+    lowercase: bool,
+}
+
+// Position of the price and the amount in a level, an index for `[price, amount]` arrays
+// or a key for `{"price": ..., "amount": ...}` objects
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LevelLayout {
+    price: Field,
+    amount: Field,
+}
+
+impl Default for LevelLayout {
+    fn default() -> Self {
+        Self {
+            price: Field::Index(0),
+            amount: Field::Index(1),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum Field {
+    Index(usize),
+    Key(String),
+}
+
+impl Field {
+    // Decimal sent as a string or a number
+    fn decimal(&self, level: &Value) -> Result<Decimal> {
+        let value = match self {
+            Self::Index(index) => level.get(index),
+            Self::Key(key) => level.get(key),
+        };
+        match value {
+            Some(Value::String(value)) => parse_decimal(value),
+            Some(Value::Number(value)) => parse_decimal(&value.to_string()),
+            _ => bail!("Invalid price level {level}"),
+        }
+    }
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TimeUnit {
+    S,
+    #[default]
+    Ms,
+    Us,
+}
+
+impl TimeUnit {
+    fn micros(&self) -> u64 {
+        match self {
+            Self::S => 1_000_000,
+            Self::Ms => 1_000,
+            Self::Us => 1,
+        }
+    }
+}
+
+// Messages of the exchange to answer, e.g. `{"op": "ping"}` with `{"op": "pong"}`
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Ping {
+    filter: HashMap<String, Value>,
+    reply: Value,
+}
@@ -0,0 +1,108 @@
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use serde_json::{json, Value};
+use std::io::Read;
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{covering, symbol, Book, Exchange, ExchangeConfig, Orderbook};
+
+const URL: &str = "wss://api.huobi.pro/ws";
+
+// Levels available on the mbp refresh channel
+const DEPTHS: [usize; 3] = [5, 10, 20];
+
+#[derive(Debug)]
+pub struct HtxExchange {
+    url: String,
+    symbol: String,
+    // Channel of the current subscription
+    channel: String,
+    replies: Vec<Message>,
+}
+
+impl HtxExchange {
+    pub fn build(config: &ExchangeConfig, symbol: &str) -> Result<Box<dyn Exchange>> {
+        Ok(Box::new(Self {
+            url: config.url(URL),
+            symbol: symbol::option(config, symbol)
+                .unwrap_or(symbol)
+                .to_lowercase(),
+            channel: String::new(),
+            replies: Vec::new(),
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl Exchange for HtxExchange {
+    fn name(&self) -> &str {
+        "HTX"
+    }
+
+    fn url(&self, _: usize) -> String {
+        self.url.clone()
+    }
+
+    fn subscribe(&mut self, depth: usize) -> Vec<Message> {
+        self.channel = format!(
+            "market.{}.mbp.refresh.{}",
+            self.symbol,
+            self.subscribed_depth(depth)
+        );
+        self.replies.clear();
+
+        let msg = json!({"sub": self.channel, "id": self.channel});
+        vec![Message::Text(msg.to_string())]
+    }
+
+    // Smallest refresh depth covering `depth`
+    fn subscribed_depth(&self, depth: usize) -> usize {
+        covering(&DEPTHS, depth)
+    }
+
+    // Every frame is gzip compressed json
+    async fn parse(&mut self, msg: Message, depth: usize) -> Result<Option<Book>> {
+        let Message::Binary(data) = msg else {
+            return Ok(None);
+        };
+        let mut text = String::new();
+        GzDecoder::new(data.as_slice())
+            .read_to_string(&mut text)
+            .context("Failed to inflate HTX frame")?;
+        let Ok(val) = serde_json::from_str::<Value>(&text) else {
+            return Ok(None);
+        };
+
+        // Connections are closed unless every ping is answered
+        if let Some(ping) = val.get("ping") {
+            self.replies = vec![Message::Text(json!({ "pong": ping }).to_string())];
+            return Ok(None);
+        }
+        if val["status"] == "error" {
+            bail!("HTX error {}: {}", val["err-code"], val["err-msg"]);
+        }
+        if val["ch"] != self.channel.as_str() {
+            return Ok(None);
+        }
+
+        let tick = serde_json::from_value::<Tick>(val["tick"].clone())
+            .context("Failed to parse HTX tick")?;
+        let mut book = tick.book.convert(depth)?;
+        book.event_time = val["ts"].as_u64().map(|ts| ts * 1000);
+        book.sequence = tick.seq_num;
+        Ok(Some(book))
+    }
+
+    fn replies(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.replies)
+    }
+}
+
+// Levels are `[price, amount]` numbers
+#[derive(Debug, serde::Deserialize)]
+struct Tick {
+    #[serde(rename = "seqNum")]
+    seq_num: Option<u64>,
+    #[serde(flatten)]
+    book: Orderbook,
+}
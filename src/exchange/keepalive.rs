@@ -0,0 +1,36 @@
+use anyhow::{bail, Context, Result};
+use tokio::time::Duration;
+
+use super::ExchangeConfig;
+
+// Keepalive of an exchange connection, set with the `ping_interval_ms` and `idle_timeout_ms`
+// exchange options
+#[derive(Clone, Debug)]
+pub struct Keepalive {
+    // Time between the pings sent on the connection
+    pub interval: Duration,
+    // Time without any message, pongs included, after which the connection is re-established
+    pub idle_timeout: Duration,
+}
+
+impl Keepalive {
+    pub fn from_config(config: &ExchangeConfig) -> Result<Self> {
+        let millis = |key: &str, default: u64| -> Result<Duration> {
+            let millis = match config.option(key) {
+                Some(value) => value
+                    .parse()
+                    .with_context(|| format!("Invalid {key} '{value}'"))?,
+                None => default,
+            };
+            if millis == 0 {
+                bail!("{key} of {} must be at least 1", config.name);
+            }
+            Ok(Duration::from_millis(millis))
+        };
+
+        Ok(Self {
+            interval: millis("ping_interval_ms", 10_000)?,
+            idle_timeout: millis("idle_timeout_ms", 30_000)?,
+        })
+    }
+}
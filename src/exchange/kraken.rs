@@ -0,0 +1,240 @@
+use anyhow::{bail, Context, Result};
+use rust_decimal::Decimal;
+use serde_json::{json, Number, Value};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::book::{LocalBook, Side};
+use super::{
+    covering, parse_decimal, symbol, Book, Disconnect, Exchange, ExchangeConfig, PriceLevel,
+};
+
+const URL: &str = "wss://ws.kraken.com/v2";
+
+// Depths available on the book channel
+const DEPTHS: [usize; 5] = [10, 25, 100, 500, 1000];
+
+// Levels per side covered by the book checksum
+const CHECKSUM_DEPTH: usize = 10;
+
+#[derive(Debug)]
+pub struct KrakenExchange {
+    url: String,
+    // Pair as `BASE/QUOTE`
+    symbol: String,
+    // Depth of the book subscription
+    depth: usize,
+    // Decimals of prices and quantities used by the checksum, learned from the instrument channel
+    precision: Option<(usize, usize)>,
+    book: LocalBook,
+    // Snapshot received on the current subscription
+    synced: bool,
+    replies: Vec<Message>,
+}
+
+impl KrakenExchange {
+    pub fn build(config: &ExchangeConfig, symbol: &str) -> Result<Box<dyn Exchange>> {
+        let symbol = symbol::format(config, symbol, "/")?;
+
+        Ok(Box::new(Self {
+            url: config.url(URL),
+            symbol,
+            depth: DEPTHS[0],
+            precision: None,
+            book: LocalBook::default(),
+            synced: false,
+            replies: Vec::new(),
+        }))
+    }
+
+    fn request(&self, method: &str, channel: &str) -> Message {
+        let params = match channel {
+            "book" => json!({"channel": "book", "symbol": [self.symbol], "depth": self.depth}),
+            _ => json!({"channel": channel}),
+        };
+        Message::Text(json!({"method": method, "params": params}).to_string())
+    }
+
+    // Precision of the pair from the instrument snapshot, then move on to the book channel.
+    // A pair missing from the snapshot never gets a book, so the connection is closed
+    fn instrument(&mut self, data: &Value) -> Result<()> {
+        let pair = data["pairs"]
+            .as_array()
+            .and_then(|pairs| {
+                pairs
+                    .iter()
+                    .find(|pair| pair["symbol"] == self.symbol.as_str())
+            })
+            .ok_or_else(|| Disconnect(format!("Unknown Kraken pair {}", self.symbol)))?;
+        let precision = |key: &str| {
+            pair[key]
+                .as_u64()
+                .map(|precision| precision as usize)
+                .with_context(|| format!("Missing {key} of Kraken pair {}", self.symbol))
+        };
+        self.precision = Some((precision("price_precision")?, precision("qty_precision")?));
+
+        self.replies = vec![
+            self.request("unsubscribe", "instrument"),
+            self.request("subscribe", "book"),
+        ];
+        Ok(())
+    }
+
+    fn apply(&mut self, kind: &str, data: BookData, depth: usize) -> Result<Option<Book>> {
+        match kind {
+            "snapshot" => {
+                self.book.clear();
+                self.synced = true;
+            }
+            // Updates of the previous subscription until the new snapshot comes in
+            _ if !self.synced => return Ok(None),
+            _ => {}
+        }
+
+        for level in &data.bids {
+            self.book.set(Side::Bid, level.convert()?);
+        }
+        for level in &data.asks {
+            self.book.set(Side::Ask, level.convert()?);
+        }
+        // Levels pushed out of the subscribed depth are not removed by the exchange
+        self.book.truncate(self.depth);
+
+        let checksum = self.checksum();
+        if checksum != data.checksum {
+            self.synced = false;
+            self.replies = vec![
+                self.request("unsubscribe", "book"),
+                self.request("subscribe", "book"),
+            ];
+            bail!(
+                "Kraken checksum mismatch for {}: expected {}, got {checksum}, resubscribing",
+                self.symbol,
+                data.checksum
+            );
+        }
+
+        let mut book = self.book.book(depth);
+        book.event_time = data
+            .timestamp
+            .as_deref()
+            .and_then(|time| chrono::DateTime::parse_from_rfc3339(time).ok())
+            .map(|time| time.timestamp_micros() as u64);
+        Ok(Some(book))
+    }
+
+    // CRC32 of the top asks then the top bids, as price and quantity at the pair precision
+    // without the decimal point and leading zeros
+    fn checksum(&self) -> u32 {
+        let (price_precision, qty_precision) = self.precision.unwrap_or_default();
+        let field = |value: Decimal, precision: usize| {
+            format!("{value:.precision$}")
+                .replace('.', "")
+                .trim_start_matches('0')
+                .to_string()
+        };
+
+        let book = self.book.book(CHECKSUM_DEPTH);
+        let payload: String = book
+            .asks
+            .iter()
+            .chain(&book.bids)
+            .map(|level| field(level.price, price_precision) + &field(level.amount, qty_precision))
+            .collect();
+        crc32fast::hash(payload.as_bytes())
+    }
+}
+
+#[tonic::async_trait]
+impl Exchange for KrakenExchange {
+    fn name(&self) -> &str {
+        "KRAKEN"
+    }
+
+    fn url(&self, _: usize) -> String {
+        self.url.clone()
+    }
+
+    // The book channel needs the pair precision for its checksum, which comes from the instrument channel
+    fn subscribe(&mut self, depth: usize) -> Vec<Message> {
+        self.depth = self.subscribed_depth(depth);
+        self.book.clear();
+        self.synced = false;
+        self.replies.clear();
+
+        match self.precision {
+            None => vec![self.request("subscribe", "instrument")],
+            Some(_) => vec![self.request("subscribe", "book")],
+        }
+    }
+
+    // Smallest book depth covering `depth`
+    fn subscribed_depth(&self, depth: usize) -> usize {
+        covering(&DEPTHS, depth)
+    }
+
+    async fn parse(&mut self, msg: Message, depth: usize) -> Result<Option<Book>> {
+        let Message::Text(text) = msg else {
+            return Ok(None);
+        };
+        let Ok(val) = serde_json::from_str::<Value>(&text) else {
+            return Ok(None);
+        };
+        if val["success"] == false {
+            bail!("Kraken {} failure: {}", val["method"], val["error"]);
+        }
+
+        match (val["channel"].as_str(), val["type"].as_str()) {
+            (Some("instrument"), Some("snapshot")) => {
+                self.instrument(&val["data"])?;
+                Ok(None)
+            }
+            (Some("book"), Some(kind)) => {
+                let Ok(data) = serde_json::from_value::<Vec<BookData>>(val["data"].clone()) else {
+                    return Ok(None);
+                };
+                let Some(data) = data.into_iter().find(|data| data.symbol == self.symbol) else {
+                    return Ok(None);
+                };
+                self.apply(kind, data, depth)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn replies(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.replies)
+    }
+
+    fn keepalive(&mut self) -> Message {
+        Message::Text(json!({"method": "ping"}).to_string())
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BookData {
+    symbol: String,
+    #[serde(default)]
+    bids: Vec<Level>,
+    #[serde(default)]
+    asks: Vec<Level>,
+    checksum: u32,
+    timestamp: Option<String>,
+}
+
+// Prices and quantities are JSON numbers
+#[derive(Debug, serde::Deserialize)]
+struct Level {
+    price: Number,
+    qty: Number,
+}
+
+impl Level {
+    fn convert(&self) -> Result<PriceLevel> {
+        Ok(PriceLevel {
+            price: parse_decimal(&self.price.to_string())
+                .context("Failed to parse Kraken price")?,
+            amount: parse_decimal(&self.qty.to_string()).context("Failed to parse Kraken qty")?,
+        })
+    }
+}
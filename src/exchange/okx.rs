@@ -0,0 +1,172 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::book::LocalBook;
+use super::{symbol, Book, Exchange, ExchangeConfig, Orderbook};
+
+const URL: &str = "wss://ws.okx.com:8443/ws/v5/public";
+
+// Levels on the books channel
+const DEPTH: usize = 400;
+
+// Levels per side covered by the book checksum
+const CHECKSUM_DEPTH: usize = 25;
+
+#[derive(Debug)]
+pub struct OkxExchange {
+    url: String,
+    // Instrument id as `BASE-QUOTE`
+    inst_id: String,
+    book: LocalBook,
+    // Sequence id of the last update applied to the book, None until the snapshot
+    seq_id: Option<i64>,
+    replies: Vec<Message>,
+}
+
+impl OkxExchange {
+    pub fn build(config: &ExchangeConfig, symbol: &str) -> Result<Box<dyn Exchange>> {
+        let inst_id = symbol::format(config, symbol, "-")?;
+
+        Ok(Box::new(Self {
+            url: config.url(URL),
+            inst_id,
+            book: LocalBook::default(),
+            seq_id: None,
+            replies: Vec::new(),
+        }))
+    }
+
+    fn request(&self, op: &str) -> Message {
+        let msg = json!({"op": op, "args": [{"channel": "books", "instId": self.inst_id}]});
+        Message::Text(msg.to_string())
+    }
+
+    fn apply(&mut self, action: &str, data: BookData, depth: usize) -> Result<Option<Book>> {
+        match (action, self.seq_id) {
+            ("snapshot", _) => self.book.reset(&data.book)?,
+            // Updates of the previous subscription until the new snapshot comes in
+            (_, None) => return Ok(None),
+            (_, Some(seq_id)) if data.prev_seq_id == seq_id => {
+                self.book.apply(&data.book)?;
+            }
+            (_, Some(seq_id)) => {
+                self.resync();
+                bail!(
+                    "OKX sequence gap for {}: expected {seq_id}, got {}, resubscribing",
+                    self.inst_id,
+                    data.prev_seq_id
+                );
+            }
+        }
+        // Levels pushed out of the channel depth are dropped
+        self.book.truncate(DEPTH);
+        self.seq_id = Some(data.seq_id);
+
+        let checksum = self.checksum();
+        if checksum != data.checksum {
+            self.resync();
+            bail!(
+                "OKX checksum mismatch for {}: expected {}, got {checksum}, resubscribing",
+                self.inst_id,
+                data.checksum
+            );
+        }
+
+        let mut book = self.book.book(depth);
+        book.event_time = data.ts.parse::<u64>().ok().map(|ts| ts * 1000);
+        book.sequence = u64::try_from(data.seq_id).ok();
+        Ok(Some(book))
+    }
+
+    // Subscribe again for a new snapshot
+    fn resync(&mut self) {
+        self.seq_id = None;
+        self.replies = vec![self.request("unsubscribe"), self.request("subscribe")];
+    }
+
+    // CRC32 of the top bids and asks interleaved as `bid:size:ask:size:...`, signed
+    fn checksum(&self) -> i32 {
+        let book = self.book.book(CHECKSUM_DEPTH);
+        let mut fields = Vec::new();
+        for index in 0..CHECKSUM_DEPTH {
+            for levels in [&book.bids, &book.asks] {
+                if let Some(level) = levels.get(index) {
+                    fields.push(level.price.to_string());
+                    fields.push(level.amount.to_string());
+                }
+            }
+        }
+        crc32fast::hash(fields.join(":").as_bytes()) as i32
+    }
+}
+
+#[tonic::async_trait]
+impl Exchange for OkxExchange {
+    fn name(&self) -> &str {
+        "OKX"
+    }
+
+    fn url(&self, _: usize) -> String {
+        self.url.clone()
+    }
+
+    fn subscribe(&mut self, _: usize) -> Vec<Message> {
+        self.book.clear();
+        self.seq_id = None;
+        self.replies.clear();
+        vec![self.request("subscribe")]
+    }
+
+    // The books channel always has the top 400 levels
+    fn subscribed_depth(&self, _: usize) -> usize {
+        DEPTH
+    }
+
+    async fn parse(&mut self, msg: Message, depth: usize) -> Result<Option<Book>> {
+        let Message::Text(text) = msg else {
+            return Ok(None);
+        };
+        let Ok(val) = serde_json::from_str::<Value>(&text) else {
+            return Ok(None);
+        };
+        if val["event"] == "error" {
+            bail!("OKX error {}: {}", val["code"], val["msg"]);
+        }
+        if val["arg"]["channel"] != "books" || val["arg"]["instId"] != self.inst_id.as_str() {
+            return Ok(None);
+        }
+        let Some(action) = val["action"].as_str() else {
+            return Ok(None);
+        };
+
+        let data = serde_json::from_value::<Vec<BookData>>(val["data"].clone())
+            .context("Failed to parse OKX book")?;
+        let mut book = None;
+        for data in data {
+            book = self.apply(action, data, depth)?;
+        }
+        Ok(book)
+    }
+
+    fn replies(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.replies)
+    }
+
+    // OKX closes connections idle for 30 seconds and answers a plain `ping` text
+    fn keepalive(&mut self) -> Message {
+        Message::Text("ping".to_string())
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BookData {
+    #[serde(flatten)]
+    book: Orderbook,
+    // Event time in milliseconds
+    ts: String,
+    checksum: i32,
+    prev_seq_id: i64,
+    seq_id: i64,
+}
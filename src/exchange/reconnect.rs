@@ -0,0 +1,132 @@
+use anyhow::{bail, Context, Result};
+use tokio::time::Duration;
+
+use super::{now_micros, ExchangeConfig};
+
+// How an exchange feed reconnects, set with the `backoff_ms`, `max_backoff_ms`, `max_attempts`
+// and `cooldown_ms` exchange options
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReconnectPolicy {
+    // Delay after the first failure, doubled with every failure in a row
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    // Failures in a row after which the circuit breaker opens and the exchange is down
+    pub max_attempts: u32,
+    // Time the circuit breaker stays open before the next attempt
+    pub cooldown: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            max_attempts: 10,
+            cooldown: Duration::from_secs(300),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn from_config(config: &ExchangeConfig) -> Result<Self> {
+        let default = Self::default();
+        let millis = |key: &str, default: Duration| -> Result<Duration> {
+            match config.option(key) {
+                Some(value) => value
+                    .parse()
+                    .map(Duration::from_millis)
+                    .with_context(|| format!("Invalid {key} '{value}'")),
+                None => Ok(default),
+            }
+        };
+
+        let policy = Self {
+            backoff: millis("backoff_ms", default.backoff)?,
+            max_backoff: millis("max_backoff_ms", default.max_backoff)?,
+            max_attempts: match config.option("max_attempts") {
+                Some(value) => value
+                    .parse()
+                    .with_context(|| format!("Invalid max_attempts '{value}'"))?,
+                None => default.max_attempts,
+            },
+            cooldown: millis("cooldown_ms", default.cooldown)?,
+        };
+        if policy.max_attempts == 0 {
+            bail!("max_attempts of {} must be at least 1", config.name);
+        }
+        Ok(policy)
+    }
+}
+
+// Reconnect statistics of an exchange feed
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReconnectStats {
+    // Successful connections after the first one
+    pub reconnects: u64,
+    // Failed connection attempts and lost connections
+    pub failures: u64,
+    // Failures since the last book
+    pub consecutive_failures: u32,
+    // Circuit breaker open, no attempt until the cooldown is over
+    pub down: bool,
+    // Time of the next connection attempt in microseconds since the epoch, 0 while connected
+    pub next_attempt: u64,
+}
+
+// Exponential backoff with jitter and a circuit breaker, following the connections of a feed
+#[derive(Debug)]
+pub struct Reconnect {
+    policy: ReconnectPolicy,
+    stats: ReconnectStats,
+    connected: bool,
+}
+
+impl Reconnect {
+    pub fn new(policy: ReconnectPolicy) -> Self {
+        Self {
+            policy,
+            stats: ReconnectStats::default(),
+            connected: false,
+        }
+    }
+
+    pub fn stats(&self) -> ReconnectStats {
+        self.stats
+    }
+
+    pub fn connected(&mut self) {
+        if self.connected {
+            self.stats.reconnects += 1;
+        }
+        self.connected = true;
+        self.stats.next_attempt = 0;
+    }
+
+    // First book of a connection, the exchange works again and the circuit breaker closes
+    pub fn recovered(&mut self) {
+        self.stats.consecutive_failures = 0;
+        self.stats.down = false;
+    }
+
+    // Count a failed attempt or a lost connection, returns the delay before the next attempt
+    pub fn failed(&mut self) -> Duration {
+        self.stats.failures += 1;
+        self.stats.consecutive_failures += 1;
+
+        // Half the backoff plus a random part of the other half, so feeds do not retry in lockstep
+        let delay = if self.stats.consecutive_failures >= self.policy.max_attempts {
+            self.stats.down = true;
+            self.policy.cooldown
+        } else {
+            let exponent = (self.stats.consecutive_failures - 1).min(31);
+            let backoff = self
+                .policy
+                .backoff
+                .saturating_mul(1 << exponent)
+                .min(self.policy.max_backoff);
+            backoff / 2 + backoff.mul_f64(fastrand::f64() / 2.0)
+        };
+        self.stats.next_attempt = now_micros() + delay.as_micros() as u64;
+        delay
+    }
+}
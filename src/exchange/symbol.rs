@@ -0,0 +1,46 @@
+use anyhow::{Context, Result};
+
+use super::ExchangeConfig;
+
+// Quote currencies recognised at the end of a trade pair, longer ones first so `usdt` wins over `usd`
+const QUOTES: &[&str] = &[
+    "FDUSD", "USDT", "USDC", "BUSD", "TUSD", "DAI", "USD", "EUR", "GBP", "JPY", "CAD", "CHF",
+    "AUD", "TRY", "BRL", "BTC", "ETH", "BNB", "SOL",
+];
+
+// Split a trade pair such as `ethbtc` or `eth-btc` into its uppercase base and quote currencies
+pub fn split(pair: &str) -> Option<(String, String)> {
+    let pair = pair.to_uppercase();
+    if let Some((base, quote)) = pair.split_once(['/', '-', '_']) {
+        return Some((base.to_string(), quote.to_string()));
+    }
+
+    QUOTES.iter().find_map(|quote| {
+        let base = pair.strip_suffix(quote)?;
+        (!base.is_empty()).then(|| (base.to_string(), quote.to_string()))
+    })
+}
+
+// Base and quote currencies of a trade pair, for exchanges formatting their own symbol
+pub fn currencies(pair: &str) -> Result<(String, String)> {
+    split(pair)
+        .with_context(|| format!("Unknown quote currency in {pair}, set the symbol.{pair} option"))
+}
+
+// Symbol of a trade pair set in the exchange config, `symbol.<pair>` or `symbol` when a single
+// trade pair is served
+pub fn option<'a>(config: &'a ExchangeConfig, pair: &str) -> Option<&'a str> {
+    config
+        .option(&format!("symbol.{pair}"))
+        .or_else(|| config.option("symbol"))
+}
+
+// Trade pair in the format of an exchange, e.g. `ETH/BTC` with the `/` separator.
+// The symbol set in the exchange config takes precedence
+pub fn format(config: &ExchangeConfig, pair: &str, separator: &str) -> Result<String> {
+    if let Some(symbol) = option(config, pair) {
+        return Ok(symbol.to_string());
+    }
+    let (base, quote) = currencies(pair)?;
+    Ok(base + separator + &quote)
+}
@@ -1,20 +1,40 @@
-use merger::Merger;
+use merger::{
+    Books, DepthGuard, DepthTracker, Merger, View, DEFAULT_DEPTH, DEFAULT_MAX_AGE, MAX_DEPTH,
+};
 use orderbook::orderbook_aggregator_server::{OrderbookAggregator, OrderbookAggregatorServer};
-use orderbook::{Empty, Summary};
+use orderbook::{Empty, FeedState, LevelView, Summary, SummaryRequest};
 
 use anyhow::Context;
 use clap::Parser;
+use exchange::keepalive::Keepalive;
+use exchange::reconnect::ReconnectPolicy;
+use exchange::ExchangeConfig;
+use futures_util::future::join_all;
 use futures_util::{Stream, StreamExt};
+use metrics::Metrics;
+use prometheus::IntGauge;
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::pin::Pin;
-use tokio::sync::broadcast::{channel, Sender};
-use tokio_stream::wrappers::BroadcastStream;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{channel, Receiver, Sender};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
 use tonic::{Request, Response, Status};
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+use tracing::{info, info_span, warn, Span};
+use tracing_subscriber::EnvFilter;
 
 pub mod exchange;
 pub mod merger;
+pub mod metrics;
 
 pub mod orderbook {
     tonic::include_proto!("orderbook");
+
+    pub const FILE_DESCRIPTOR_SET: &[u8] =
+        tonic::include_file_descriptor_set!("orderbook_descriptor");
 }
 
 #[cfg(test)]
@@ -22,63 +42,224 @@ pub mod tests;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
-struct Cli {
-    // Required trade pair
-    #[clap(long, value_parser, default_value = "ethbtc")]
-    trade_pair: String,
-
-    // URL for ws connection from Binance
-    #[clap(long, value_parser, default_value = "wss://stream.binance.com:9443")]
-    binance_url: String,
+pub struct Cli {
+    // Trade pairs to serve as `pair[:depth]`, comma separated or repeated
+    #[clap(
+        long = "trade-pair",
+        value_parser,
+        value_delimiter = ',',
+        default_value = "ethbtc"
+    )]
+    trade_pairs: Vec<String>,
 
-    // URL for ws connection from bitstamp
-    #[clap(long, value_parser, default_value = "wss://ws.bitstamp.net")]
-    bitstamp_url: String,
+    // Exchanges to aggregate as `name[,key=value...]`, e.g. `bitstamp,url=wss://ws.bitstamp.net`
+    #[clap(long = "exchange", value_parser, default_values = ["binance", "bitstamp"])]
+    exchanges: Vec<ExchangeConfig>,
 
     // Port for gRPC server
     #[clap(long, value_parser, default_value = "7050")]
     port: String,
+
+    // Milliseconds without a book after which an exchange is stale and left out of the merged book
+    #[clap(
+        long = "max-age-ms",
+        value_parser = clap::value_parser!(u64).range(1..),
+        default_value_t = DEFAULT_MAX_AGE.as_millis() as u64
+    )]
+    max_age_ms: u64,
+
+    // Milliseconds to wait for the first book of every exchange before serving
+    #[clap(long = "startup-timeout-ms", default_value_t = 10000)]
+    startup_timeout_ms: u64,
+
+    // What to do when an exchange has no book after the startup timeout
+    #[clap(long = "startup-policy", value_enum, default_value_t = StartupPolicy::Partial)]
+    startup_policy: StartupPolicy,
+
+    // Log filter such as `info` or `orderbook_aggregator=debug`, RUST_LOG takes precedence when set
+    #[clap(long = "log-level", default_value = "info")]
+    log_level: String,
+
+    #[clap(long = "log-format", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    // Port of the Prometheus `/metrics` endpoint, not served when not set
+    #[clap(long = "metrics-port")]
+    metrics_port: Option<u16>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum StartupPolicy {
+    // Serve with the exchanges that are ready, the others join when they are
+    Partial,
+    // Exit with an error
+    FailFast,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    Text,
+    // One JSON object per line, with the fields of the current spans
+    Json,
 }
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    init_logging(&cli.log_level, cli.log_format)?;
 
-    run(cli.trade_pair, cli.binance_url, cli.bitstamp_url, cli.port).await?;
+    run(cli).await?;
 
     Ok(())
 }
 
-pub async fn run(
-    trade_pair: String,
-    binance_url: String,
-    bitstamp_url: String,
-    port: String,
-) -> anyhow::Result<()> {
-    // Channel for merged orderbooks
-    let (sender, _) = channel(1);
+pub async fn run(cli: Cli) -> anyhow::Result<()> {
+    let Cli {
+        trade_pairs,
+        exchanges,
+        port,
+        max_age_ms,
+        startup_timeout_ms,
+        startup_policy,
+        log_level: _,
+        log_format: _,
+        metrics_port,
+    } = cli;
+    let trade_pairs = trade_pairs
+        .iter()
+        .map(|spec| parse_trade_pair(spec))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // Pairs are served by name, a second one would replace the first
+    for (index, (pair, _)) in trade_pairs.iter().enumerate() {
+        if trade_pairs[..index].iter().any(|(other, _)| other == pair) {
+            anyhow::bail!("Trade pair {pair} is configured more than once");
+        }
+    }
+    let default_pair = trade_pairs
+        .first()
+        .context("At least one trade pair is required")?
+        .0
+        .clone();
 
-    // Channels for binance orderbooks and bitstamp orderooks
-    let (sender1, _) = channel(1);
-    let (sender2, _) = channel(1);
+    // A plain `symbol` option would subscribe every pair to the same exchange symbol
+    if trade_pairs.len() > 1 {
+        if let Some(config) = exchanges
+            .iter()
+            .find(|config| config.option("symbol").is_some())
+        {
+            anyhow::bail!(
+                "The symbol option of {} is for a single trade pair, set symbol.<pair> for each pair",
+                config.name
+            );
+        }
+    }
 
-    // Start receiving from Binance
-    exchange::binance::BinanceExchange::start(trade_pair.clone(), binance_url, sender1.clone())
-        .await
-        .context("Failed to start Binance receiver")?;
+    let metrics = Metrics::new();
+    if let Some(port) = metrics_port {
+        metrics.clone().serve(port)?;
+        info!(port, "serving metrics");
+    }
 
-    // Start receiving from Bitstamp
-    exchange::bitstamp::BitstampExchange::start(trade_pair, bitstamp_url, sender2.clone())
-        .await
-        .context("Failed to start Bitstamp receiver")?;
+    // Serving status of every trade pair, e.g. `ethbtc`, and of the aggregator service
+    let (mut health, health_server) = tonic_health::server::health_reporter();
+    health
+        .set_serving::<OrderbookAggregatorServer<GRPC>>()
+        .await;
+
+    // Channel for the exchange orderbooks of every trade pair
+    let mut pairs = HashMap::new();
+    let mut handles = Vec::new();
+    for (trade_pair, depth) in trade_pairs {
+        let (sender, _) = channel(1);
+        report_health(trade_pair.clone(), sender.subscribe(), health.clone()).await;
+
+        // Parent of the spans of the exchange feeds
+        let _span = info_span!("pair", symbol = %trade_pair).entered();
+        let depth = DepthTracker::new(depth);
+
+        // One channel of orderbooks per exchange, keyed by exchange name
+        let mut feeds = Vec::new();
+        for config in &exchanges {
+            let exchange = exchange::build(config, &trade_pair).with_context(|| {
+                format!("Failed to start {} receiver for {trade_pair}", config.name)
+            })?;
+            // Feeds are keyed by name, a second one would replace the first in the merger
+            if feeds.iter().any(|(name, _)| name == exchange.name()) {
+                anyhow::bail!(
+                    "Exchange {} is configured more than once for {trade_pair}",
+                    exchange.name()
+                );
+            }
+            let policy = ReconnectPolicy::from_config(config)?;
+            let keepalive = Keepalive::from_config(config)?;
+
+            let feed_metrics = metrics.feed(exchange.name(), &trade_pair);
 
-    Merger::processor(sender1.subscribe(), sender2.subscribe(), sender.clone());
+            let (exchange_sender, receiver) = channel(1);
+            feeds.push((exchange.name().to_string(), receiver));
 
-    let server = OrderbookAggregatorServer::new(GRPC { sender });
+            // Start receiving from the exchange
+            let handle = exchange::start(
+                exchange,
+                policy,
+                keepalive,
+                feed_metrics,
+                exchange_sender,
+                depth.receiver(),
+            );
+            handles.push((trade_pair.clone(), handle));
+        }
+
+        Merger::processor(
+            trade_pair.clone(),
+            feeds,
+            sender.clone(),
+            Duration::from_millis(max_age_ms),
+            metrics.clone(),
+        );
+        pairs.insert(trade_pair, TradePair { sender, depth });
+    }
+
+    // Wait for the first book of every exchange
+    let timeout = Duration::from_millis(startup_timeout_ms);
+    let ready = join_all(handles.iter_mut().map(|(_, handle)| handle.ready(timeout))).await;
+    let pending = handles
+        .iter()
+        .zip(ready)
+        .filter(|(_, ready)| !ready)
+        .map(|((pair, handle), _)| match handle.error() {
+            Some(err) => format!("{} {pair} ({err})", handle.name()),
+            None => format!("{} {pair}", handle.name()),
+        })
+        .collect::<Vec<_>>();
+    if !pending.is_empty() {
+        let pending = pending.join(", ");
+        match startup_policy {
+            StartupPolicy::Partial => warn!(%pending, "serving without a book from every exchange"),
+            StartupPolicy::FailFast => {
+                anyhow::bail!("No book within {timeout:?} from {pending}")
+            }
+        }
+    }
+
+    let server = OrderbookAggregatorServer::new(GRPC {
+        pairs,
+        default_pair,
+        metrics,
+    });
+
+    let reflection = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(orderbook::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .build()
+        .context("Failed to build gRPC reflection service")?;
 
     // Start GRPC server
     let port = port.parse::<u16>().unwrap_or(7050);
+    info!(port, "serving gRPC");
     tonic::transport::Server::builder()
         .add_service(server)
+        .add_service(health_server)
+        .add_service(reflection)
         .serve(std::net::SocketAddr::from(([127, 0, 0, 1], port)))
         .await
         .context("Failed to satrt gRPC server")?;
@@ -86,32 +267,212 @@ pub async fn run(
     Ok(())
 }
 
+// Health of a trade pair, NOT_SERVING until at least one exchange is live
+async fn report_health(symbol: String, receiver: Receiver<Arc<Books>>, mut health: HealthReporter) {
+    health
+        .set_service_status(&symbol, ServingStatus::NotServing)
+        .await;
+
+    let mut serving = false;
+    let mut books = BroadcastStream::new(receiver);
+    tokio::spawn(async move {
+        while let Some(next) = books.next().await {
+            // Lagged receivers skip to the newest books
+            let Ok(books) = next else { continue };
+            let live = books
+                .exchanges
+                .values()
+                .any(|exchange| exchange.state == FeedState::Live);
+            if live != serving {
+                serving = live;
+                let status = match live {
+                    true => ServingStatus::Serving,
+                    false => ServingStatus::NotServing,
+                };
+                info!(%symbol, %status, "health changed");
+                health.set_service_status(&symbol, status).await;
+            }
+        }
+    });
+}
+
+// Log to stderr at the level of RUST_LOG, or `level` when it is not set
+fn init_logging(level: &str, format: LogFormat) -> anyhow::Result<()> {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => {
+            EnvFilter::try_new(level).with_context(|| format!("Invalid log level '{level}'"))?
+        }
+    };
+    let logger = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => logger.init(),
+        LogFormat::Json => logger.json().with_span_list(true).init(),
+    }
+    Ok(())
+}
+
+// Parse `pair[:depth]` into the lowercase trade pair and its depth
+fn parse_trade_pair(spec: &str) -> anyhow::Result<(String, usize)> {
+    let (pair, depth) = match spec.split_once(':') {
+        Some((pair, depth)) => (
+            pair,
+            depth
+                .parse::<usize>()
+                .with_context(|| format!("Invalid depth in trade pair '{spec}'"))?,
+        ),
+        None => (spec, DEFAULT_DEPTH),
+    };
+    if depth == 0 || depth > MAX_DEPTH {
+        anyhow::bail!("Depth of {pair} must be between 1 and {MAX_DEPTH}");
+    }
+
+    Ok((pair.trim().to_lowercase(), depth))
+}
+
+// Channels of a served trade pair
+#[derive(Debug)]
+struct TradePair {
+    // Exchange orderbooks
+    sender: Sender<Arc<Books>>,
+    // Depth needed by the subscribers
+    depth: Arc<DepthTracker>,
+}
+
 // GRPC server method implementation
 #[derive(Debug)]
 pub struct GRPC {
-    sender: Sender<Summary>,
+    // Served trade pairs
+    pairs: HashMap<String, TradePair>,
+    // Trade pair served when the client does not pick one
+    default_pair: String,
+    metrics: Arc<Metrics>,
+}
+
+impl GRPC {
+    // Trade pair requested in the `symbol` metadata, or the default one
+    fn symbol<T>(&self, request: &Request<T>) -> String {
+        match request.metadata().get("symbol") {
+            Some(symbol) => symbol.to_str().unwrap_or_default().to_lowercase(),
+            None => self.default_pair.clone(),
+        }
+    }
+
+    // Stream of merged orderbooks for the view of a trade pair, None for unknown pairs.
+    // Depth 0 serves the depth configured for the trade pair
+    fn stream(
+        &self,
+        symbol: &str,
+        mut view: View,
+        peer: Option<SocketAddr>,
+    ) -> Option<BookSummaryStream> {
+        let pair = self.pairs.get(symbol)?;
+        let receiever = pair.sender.subscribe();
+
+        view.depth = match view.depth {
+            0 => pair.depth.default_depth(),
+            depth => depth.min(MAX_DEPTH),
+        };
+        let span = info_span!(
+            "subscriber",
+            symbol,
+            peer = ?peer,
+            depth = view.depth,
+            consolidated = view.consolidated
+        );
+        info!(parent: &span, exchanges = ?view.exchanges, "subscribed");
+        // Exchanges provide the depth for as long as the stream is alive
+        let subscription =
+            Subscription::new(pair.depth.register(view.depth), span, symbol, &self.metrics);
+        let metrics = self.metrics.clone();
+        let merge_seconds = metrics.merge_seconds.with_label_values(&[symbol]);
+        let lagged = metrics.lagged.with_label_values(&["subscriber", symbol]);
+
+        // Conversion of Receiver<Arc<Books>> into Stream<Result<Summary, Status>>
+        let result = BroadcastStream::new(receiever).filter_map(move |r| {
+            std::future::ready(match r {
+                Ok(books) => {
+                    let _timer = merge_seconds.start_timer();
+                    Some(Ok::<_, _>(Merger::merge(&books, &view)))
+                }
+                Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                    lagged.inc_by(skipped);
+                    warn!(parent: &subscription.span, skipped, "subscriber lagged");
+                    None
+                }
+            })
+        });
+
+        Some(Box::pin(result))
+    }
+}
+
+// Subscriber of a stream, counted in the subscribers gauge and logged until the stream is dropped
+struct Subscription {
+    _depth: DepthGuard,
+    span: Span,
+    gauge: IntGauge,
 }
 
+impl Subscription {
+    fn new(depth: DepthGuard, span: Span, symbol: &str, metrics: &Metrics) -> Self {
+        let gauge = metrics.subscribers.with_label_values(&[symbol]);
+        gauge.inc();
+        Self {
+            _depth: depth,
+            span,
+            gauge,
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.gauge.dec();
+        info!(parent: &self.span, "unsubscribed");
+    }
+}
+
+type BookSummaryStream = Pin<Box<dyn Stream<Item = Result<Summary, Status>> + Send>>;
+
 #[tonic::async_trait]
 impl OrderbookAggregator for GRPC {
-    type BookSummaryStream = Pin<Box<dyn Stream<Item = Result<Summary, Status>> + Send>>;
+    type BookSummaryStream = BookSummaryStream;
+    type SymbolBookSummaryStream = BookSummaryStream;
 
     async fn book_summary(
         &self,
-        _: Request<Empty>,
+        request: Request<Empty>,
     ) -> Result<Response<Self::BookSummaryStream>, Status> {
-        let receiever = self.sender.subscribe();
+        let symbol = self.symbol(&request);
+        let view = View {
+            depth: 0,
+            ..<_>::default()
+        };
+        match self.stream(&symbol, view, request.remote_addr()) {
+            Some(stream) => Ok(Response::new(stream)),
+            None => Err(Status::not_found(format!("Unknown symbol: {symbol}"))),
+        }
+    }
 
-        // Conversion of Receiver<Summary> into Stream<Receiver<Result<Summary, Status>>>
-        let result = BroadcastStream::new(receiever).filter_map(|r| {
-            std::future::ready(match r {
-                Ok(r) => Some(Ok::<_, _>(r)),
-                _ => None,
-            })
-        });
+    async fn symbol_book_summary(
+        &self,
+        request: Request<SummaryRequest>,
+    ) -> Result<Response<Self::SymbolBookSummaryStream>, Status> {
+        let peer = request.remote_addr();
+        let request = request.into_inner();
+        let symbol = request.symbol.to_lowercase();
 
-        Ok(tonic::Response::new(
-            Box::pin(result) as Self::BookSummaryStream
-        ))
+        let view = View {
+            depth: request.depth as usize,
+            consolidated: request.view() == LevelView::Consolidated,
+            exchanges: request.exchanges,
+        };
+        match self.stream(&symbol, view, peer) {
+            Some(stream) => Ok(Response::new(stream)),
+            None => Err(Status::not_found(format!("Unknown symbol: {symbol}"))),
+        }
     }
 }
@@ -1,87 +1,428 @@
-use tokio::sync::broadcast::{error::RecvError, Receiver, Sender};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tokio_stream::{StreamExt, StreamMap};
+use tracing::{info, info_span, warn, Instrument};
 
-use crate::orderbook::Summary;
+use crate::exchange::reconnect::ReconnectStats;
+use crate::exchange::{Book, Feed, PriceLevel};
+use crate::metrics::Metrics;
+use crate::orderbook::{ExchangeStatus, FeedState, Level, Summary, VenueQuantity};
+
+// Depth of a trade pair when the config does not set one
+pub const DEFAULT_DEPTH: usize = 10;
+
+// Largest depth a subscriber can ask for
+pub const MAX_DEPTH: usize = 5000;
+
+// Age of the last book after which an exchange is stale, when the config does not set one
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(10);
+
+// Latest book of every exchange for a trade pair
+#[derive(Clone, Debug, Default)]
+pub struct Books {
+    pub symbol: String,
+    // Increases with every published update
+    pub sequence: u64,
+    pub exchanges: BTreeMap<String, ExchangeBook>,
+}
+
+// Latest book of an exchange with the state of its feed
+#[derive(Clone, Debug, Default)]
+pub struct ExchangeBook {
+    pub book: Book,
+    pub state: FeedState,
+    // Time the last book was received, None until the first one
+    pub updated: Option<Instant>,
+    pub reconnect: ReconnectStats,
+}
+
+impl ExchangeBook {
+    pub fn live(book: Book) -> Self {
+        Self {
+            book,
+            state: FeedState::Live,
+            updated: Some(Instant::now()),
+            reconnect: ReconnectStats::default(),
+        }
+    }
+
+    // Apply a feed update, false if nothing changed
+    fn update(&mut self, feed: Feed) -> bool {
+        let (state, reconnect) = match feed {
+            Feed::Book(book) => {
+                // A book closes the circuit breaker, as in the feed
+                let reconnect = ReconnectStats {
+                    consecutive_failures: 0,
+                    down: false,
+                    ..self.reconnect
+                };
+                *self = Self {
+                    reconnect,
+                    ..Self::live(book)
+                };
+                return true;
+            }
+            // Subscribed again for a larger depth, the book stays valid until the next one
+            Feed::Connected(reconnect) if self.state == FeedState::Live => {
+                (FeedState::Live, reconnect)
+            }
+            Feed::Connected(reconnect) => (FeedState::Connecting, reconnect),
+            Feed::Disconnected(reconnect) => {
+                self.clear();
+                let state = match reconnect.down {
+                    true => FeedState::Down,
+                    false => FeedState::Disconnected,
+                };
+                (state, reconnect)
+            }
+        };
+        let changed = self.state != state || self.reconnect != reconnect;
+        self.state = state;
+        self.reconnect = reconnect;
+        changed
+    }
+
+    // Drop the book when the last one is older than `max_age`, true if it became stale
+    fn expire(&mut self, max_age: Duration) -> bool {
+        let expired = self.state == FeedState::Live
+            && self
+                .updated
+                .is_some_and(|updated| updated.elapsed() > max_age);
+        if expired {
+            self.clear();
+            self.state = FeedState::Stale;
+        }
+        expired
+    }
+
+    // Drop the levels, keeping the timestamps of the last book
+    fn clear(&mut self) {
+        self.book.bids.clear();
+        self.book.asks.clear();
+    }
+}
+
+// Part of the merged book a subscriber asked for
+#[derive(Clone, Debug)]
+pub struct View {
+    pub depth: usize,
+    // Exchanges to merge, all of them when empty
+    pub exchanges: Vec<String>,
+    // Combine the levels of equal price into one level
+    pub consolidated: bool,
+}
+
+impl Default for View {
+    fn default() -> Self {
+        Self {
+            depth: DEFAULT_DEPTH,
+            exchanges: Vec::new(),
+            consolidated: false,
+        }
+    }
+}
+
+impl View {
+    fn includes(&self, exchange: &str) -> bool {
+        self.exchanges.is_empty()
+            || self
+                .exchanges
+                .iter()
+                .any(|e| e.eq_ignore_ascii_case(exchange))
+    }
+}
+
+// Depth the exchanges of a trade pair have to provide, the largest of the
+// configured depth and the depths of the active subscribers
+#[derive(Debug)]
+pub struct DepthTracker {
+    default: usize,
+    // Number of active subscribers per requested depth
+    active: Mutex<BTreeMap<usize, usize>>,
+    sender: watch::Sender<usize>,
+}
+
+impl DepthTracker {
+    pub fn new(default: usize) -> Arc<Self> {
+        Arc::new(Self {
+            default,
+            active: Mutex::default(),
+            sender: watch::channel(default).0,
+        })
+    }
+
+    // Depth served to subscribers that do not ask for one
+    pub fn default_depth(&self) -> usize {
+        self.default
+    }
+
+    // Receiver of the required depth, updated whenever it changes
+    pub fn receiver(&self) -> watch::Receiver<usize> {
+        self.sender.subscribe()
+    }
+
+    // Account for a subscriber until the guard is dropped
+    pub fn register(self: &Arc<Self>, depth: usize) -> DepthGuard {
+        let mut active = self.active.lock().unwrap();
+        *active.entry(depth).or_default() += 1;
+        self.update(&active);
+
+        DepthGuard {
+            tracker: Arc::clone(self),
+            depth,
+        }
+    }
+
+    fn update(&self, active: &BTreeMap<usize, usize>) {
+        let depth = active
+            .keys()
+            .next_back()
+            .map_or(self.default, |&depth| depth.max(self.default));
+        self.sender.send_if_modified(|current| {
+            let modified = *current != depth;
+            *current = depth;
+            modified
+        });
+    }
+}
+
+// Keeps the depth of a subscriber accounted for while alive
+#[derive(Debug)]
+pub struct DepthGuard {
+    tracker: Arc<DepthTracker>,
+    depth: usize,
+}
+
+impl Drop for DepthGuard {
+    fn drop(&mut self) {
+        let mut active = self.tracker.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.depth) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.depth);
+            }
+        }
+        self.tracker.update(&active);
+    }
+}
 
 #[derive(Debug)]
 pub struct Merger {}
 
 impl Merger {
-    // recieve from both Binance and Bitstamp channels and merge and push to final channel whenever newer data comes in
+    // recieve from all exchange channels and push the latest books of every exchange to final channel whenever newer data comes in,
+    // books older than `max_age` are dropped until the exchange sends a new one
     pub fn processor(
-        mut binance_rec: Receiver<Summary>,
-        mut bitstamp_rec: Receiver<Summary>,
-        sender: Sender<Summary>,
+        symbol: String,
+        feeds: Vec<(String, Receiver<Feed>)>,
+        sender: Sender<Arc<Books>>,
+        max_age: Duration,
+        metrics: Arc<Metrics>,
     ) {
-        let mut summaries: Vec<Summary> = vec![Summary::default(); 2];
+        let mut books = Books {
+            symbol,
+            sequence: 0,
+            exchanges: feeds
+                .iter()
+                .map(|(name, _)| (name.clone(), ExchangeBook::default()))
+                .collect(),
+        };
 
-        tokio::spawn(async move {
-            loop {
-                // await futures for the first new summary recieved from Binance or Bitstamp
-                tokio::select! {
-                    summary = binance_rec.recv() => {
-                        match summary {
-                            Ok(summary) => {
-                                summaries[0] = summary;
-                                let merged = Self::merge_summaries(&summaries);
-                                // Send merged summary to gRPC channel
-                                _ = sender.send(merged);
-                            }
-                            Err(RecvError::Lagged(_)) => {}
-                            Err(RecvError::Closed) => {
-                                eprintln!("Binance channel closed");
-                            }
-                        }
+        let mut feeds: StreamMap<String, BroadcastStream<Feed>> = feeds
+            .into_iter()
+            .map(|(name, receiver)| (name, BroadcastStream::new(receiver)))
+            .collect();
 
-                    },
-                    summary = bitstamp_rec.recv() => {
-                        match summary {
-                            Ok(summary) => {
-                                summaries[1] = summary;
-                                let merged = Self::merge_summaries(&summaries);
-                                // Send merged summary to gRPC channel
-                                _ = sender.send(merged);
+        let span = info_span!("merger", symbol = %books.symbol);
+        let lagged = metrics.lagged.with_label_values(&["merger", &books.symbol]);
+        let spread = metrics.spread.with_label_values(&[&books.symbol]);
+        let top = View {
+            depth: 1,
+            ..View::default()
+        };
+        let merger = async move {
+            let mut expiry = tokio::time::interval(max_age / 4);
+            loop {
+                let changed = tokio::select! {
+                    // await futures for the first new book recieved from any exchange
+                    next = feeds.next() => {
+                        let Some((name, feed)) = next else { break };
+                        // Lagged receivers skip to the newest book
+                        let feed = match feed {
+                            Ok(feed) => feed,
+                            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                                lagged.inc_by(skipped);
+                                continue;
                             }
-                            Err(RecvError::Lagged(_)) => {}
-                            Err(RecvError::Closed) => {
-                                eprintln!("Bitstamp channel closed");
+                        };
+                        books.exchanges.entry(name).or_default().update(feed)
+                    }
+                    _ = expiry.tick() => {
+                        let mut expired = false;
+                        for (name, exchange) in &mut books.exchanges {
+                            if exchange.expire(max_age) {
+                                warn!(exchange = %name, ?max_age, "book is stale");
+                                expired = true;
                             }
                         }
+                        expired
                     }
+                };
+                if changed {
+                    books.sequence += 1;
+                    let merged = Self::merge(&books, &top);
+                    if !merged.bids.is_empty() && !merged.asks.is_empty() {
+                        spread.set(merged.spread);
+                    }
+                    // Send latest books to gRPC channel
+                    _ = sender.send(Arc::new(books.clone()));
                 }
             }
-        });
+            info!("exchange channels closed");
+        };
+        tokio::spawn(merger.instrument(span));
     }
 
-    // sorts bids asks and discard after depth 10, also calculates spread
-    fn merge_summaries(summaries: &[Summary]) -> Summary {
-        let mut result = Summary::default();
-        for s in summaries {
-            result.bids.extend(s.bids.clone());
-            result.asks.extend(s.asks.clone());
+    // merge the books of the live exchanges in the view
+    pub fn merge(books: &Books, view: &View) -> Summary {
+        let exchanges = books
+            .exchanges
+            .iter()
+            .filter(|(name, _)| view.includes(name));
+
+        let mut merged = Self::merge_books(
+            exchanges
+                .clone()
+                .filter(|(_, exchange)| exchange.state == FeedState::Live)
+                .map(|(name, exchange)| (name, &exchange.book)),
+            view,
+        );
+        merged.symbol = books.symbol.clone();
+        merged.sequence = books.sequence;
+        merged.exchanges = exchanges
+            .map(|(name, exchange)| ExchangeStatus {
+                exchange: name.clone(),
+                state: exchange.state.into(),
+                exchange_time: exchange.book.event_time.unwrap_or_default(),
+                receive_time: exchange.book.receive_time,
+                exchange_sequence: exchange.book.sequence.unwrap_or_default(),
+                reconnects: exchange.reconnect.reconnects,
+                failures: exchange.reconnect.failures,
+                consecutive_failures: exchange.reconnect.consecutive_failures,
+                next_attempt_time: exchange.reconnect.next_attempt,
+            })
+            .collect();
+        merged
+    }
+
+    // sorts bids asks and discard after depth, also calculates spread
+    fn merge_books<'a>(
+        books: impl Iterator<Item = (&'a String, &'a Book)>,
+        view: &View,
+    ) -> Summary {
+        let mut bids: Vec<(&str, PriceLevel)> = Vec::new();
+        let mut asks: Vec<(&str, PriceLevel)> = Vec::new();
+        for (exchange, book) in books {
+            bids.extend(book.bids.iter().map(|level| (exchange.as_str(), *level)));
+            asks.extend(book.asks.iter().map(|level| (exchange.as_str(), *level)));
         }
 
-        result.asks.sort_by(|first, second| {
+        asks.sort_by(|(_, first), (_, second)| {
             first
                 .price
-                .total_cmp(&second.price)
-                .then(first.amount.total_cmp(&second.amount).reverse())
+                .cmp(&second.price)
+                .then(first.amount.cmp(&second.amount).reverse())
         });
 
-        result.bids.sort_by(|first, second| {
+        bids.sort_by(|(_, first), (_, second)| {
             second
                 .price
-                .total_cmp(&first.price)
-                .then(first.amount.total_cmp(&second.amount).reverse())
+                .cmp(&first.price)
+                .then(first.amount.cmp(&second.amount).reverse())
         });
 
-        result.bids.truncate(10);
-        result.asks.truncate(10);
+        let mut result = Summary::default();
+        if let (Some((_, bid)), Some((_, ask))) = (bids.first(), asks.first()) {
+            let spread = ask.price - bid.price;
+            result.spread = to_f64(spread);
+            result.spread_decimal = spread.to_string();
+        }
 
-        if !result.bids.is_empty() && !result.asks.is_empty() {
-            result.spread = result.asks[0].price - result.bids[0].price;
+        if view.consolidated {
+            result.bids = Self::consolidate(bids, view.depth);
+            result.asks = Self::consolidate(asks, view.depth);
+        } else {
+            bids.truncate(view.depth);
+            asks.truncate(view.depth);
+            result.bids = bids.into_iter().map(Self::make_level).collect();
+            result.asks = asks.into_iter().map(Self::make_level).collect();
         }
 
-        // println!("{:?}", result);
         result
     }
+
+    // combine sorted levels of equal price into one level with the total amount
+    // and the amount of every exchange, keeps `depth` price levels
+    fn consolidate(levels: Vec<(&str, PriceLevel)>, depth: usize) -> Vec<Level> {
+        // Total of every price level with the exchange levels it combines
+        let mut consolidated: Vec<(PriceLevel, Vec<(&str, Decimal)>)> = Vec::new();
+        for (exchange, level) in levels {
+            if let Some((total, venues)) = consolidated.last_mut() {
+                if total.price == level.price {
+                    total.amount += level.amount;
+                    venues.push((exchange, level.amount));
+                    continue;
+                }
+            }
+            if consolidated.len() == depth {
+                break;
+            }
+            consolidated.push((level, vec![(exchange, level.amount)]));
+        }
+
+        consolidated
+            .into_iter()
+            .map(|(total, venues)| Level {
+                exchange: venues
+                    .iter()
+                    .map(|(exchange, _)| *exchange)
+                    .collect::<Vec<_>>()
+                    .join(","),
+                price: to_f64(total.price),
+                amount: to_f64(total.amount),
+                price_decimal: total.price.to_string(),
+                amount_decimal: total.amount.to_string(),
+                venues: venues
+                    .into_iter()
+                    .map(|(exchange, amount)| VenueQuantity {
+                        exchange: exchange.to_string(),
+                        amount: to_f64(amount),
+                        amount_decimal: amount.to_string(),
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    fn make_level((exchange, level): (&str, PriceLevel)) -> Level {
+        Level {
+            exchange: exchange.to_string(),
+            price: to_f64(level.price),
+            amount: to_f64(level.amount),
+            price_decimal: level.price.to_string(),
+            amount_decimal: level.amount.to_string(),
+            venues: Vec::new(),
+        }
+    }
+}
+
+// Convenience value for clients that do not need exact decimals
+fn to_f64(value: Decimal) -> f64 {
+    value.to_f64().unwrap_or_default()
 }
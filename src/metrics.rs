@@ -0,0 +1,159 @@
+use anyhow::Context;
+use axum::{extract::State, routing::get, Router};
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::error;
+
+// Prometheus metrics of the aggregator, served on `/metrics`
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    // Per exchange and trade pair
+    messages: IntCounterVec,
+    parse_failures: IntCounterVec,
+    reconnects: IntCounterVec,
+    pings: IntCounterVec,
+    pongs: IntCounterVec,
+    idle_timeouts: IntCounterVec,
+    resyncs: IntCounterVec,
+    // Broadcast messages skipped by slow receivers, per stage and trade pair
+    pub lagged: IntCounterVec,
+    // Per trade pair
+    pub subscribers: IntGaugeVec,
+    pub merge_seconds: HistogramVec,
+    pub spread: GaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Arc<Self> {
+        let registry = Registry::new_custom(Some("orderbook".to_string()), None).unwrap();
+        let feed = ["exchange", "symbol"];
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            register(
+                &registry,
+                IntCounterVec::new(Opts::new(name, help), labels).unwrap(),
+            )
+        };
+
+        Arc::new(Self {
+            messages: counter("messages_total", "Websocket messages received", &feed),
+            parse_failures: counter(
+                "parse_failures_total",
+                "Exchange messages that failed to parse",
+                &feed,
+            ),
+            reconnects: counter(
+                "reconnects_total",
+                "Failed connection attempts and lost connections, each followed by a reconnect",
+                &feed,
+            ),
+            pings: counter("pings_total", "Keepalive pings sent", &feed),
+            pongs: counter("pongs_total", "Websocket pongs received", &feed),
+            idle_timeouts: counter(
+                "idle_timeouts_total",
+                "Connections re-established after the idle timeout",
+                &feed,
+            ),
+            resyncs: counter(
+                "resyncs_total",
+                "Books reset by a snapshot in the middle of the stream",
+                &feed,
+            ),
+            lagged: counter(
+                "lagged_total",
+                "Messages skipped by a receiver that fell behind, in the merger or a subscriber stream",
+                &["stage", "symbol"],
+            ),
+            subscribers: register(
+                &registry,
+                IntGaugeVec::new(Opts::new("subscribers", "Active gRPC subscribers"), &["symbol"])
+                    .unwrap(),
+            ),
+            merge_seconds: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new("merge_seconds", "Time to merge a book for a subscriber")
+                        .buckets(prometheus::exponential_buckets(0.000_01, 4.0, 8).unwrap()),
+                    &["symbol"],
+                )
+                .unwrap(),
+            ),
+            spread: register(
+                &registry,
+                GaugeVec::new(
+                    Opts::new("spread", "Spread of the merged book of all live exchanges"),
+                    &["symbol"],
+                )
+                .unwrap(),
+            ),
+            registry,
+        })
+    }
+
+    // Counters of an exchange feed for a trade pair
+    pub fn feed(&self, exchange: &str, symbol: &str) -> FeedMetrics {
+        let labels = [exchange, symbol];
+        FeedMetrics {
+            messages: self.messages.with_label_values(&labels),
+            parse_failures: self.parse_failures.with_label_values(&labels),
+            reconnects: self.reconnects.with_label_values(&labels),
+            pings: self.pings.with_label_values(&labels),
+            pongs: self.pongs.with_label_values(&labels),
+            idle_timeouts: self.idle_timeouts.with_label_values(&labels),
+            resyncs: self.resyncs.with_label_values(&labels),
+        }
+    }
+
+    // Metrics in the Prometheus text format
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    // Serve the metrics on `/metrics` in the background
+    pub fn serve(self: Arc<Self>, port: u16) -> anyhow::Result<()> {
+        let app = Router::new()
+            .route("/metrics", get(Self::handler))
+            .with_state(self);
+        let server = axum::Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], port)))
+            .context("Failed to start metrics server")?
+            .serve(app.into_make_service());
+
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                error!(error = %err, "metrics server failure");
+            }
+        });
+        Ok(())
+    }
+
+    async fn handler(State(metrics): State<Arc<Self>>) -> String {
+        metrics.encode()
+    }
+}
+
+// Register a metric, the names are unique so it cannot fail
+fn register<M: Collector + Clone + 'static>(registry: &Registry, metric: M) -> M {
+    registry.register(Box::new(metric.clone())).unwrap();
+    metric
+}
+
+// Counters of an exchange feed
+#[derive(Clone, Debug)]
+pub struct FeedMetrics {
+    pub messages: IntCounter,
+    pub parse_failures: IntCounter,
+    pub reconnects: IntCounter,
+    pub pings: IntCounter,
+    pub pongs: IntCounter,
+    pub idle_timeouts: IntCounter,
+    pub resyncs: IntCounter,
+}
@@ -2,75 +2,226 @@
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        Path, Query, State,
     },
+    http::Uri,
     response::IntoResponse,
-    routing::get,
-    Router,
+    routing::{get, MethodRouter},
+    Json, Router,
 };
 use std::{
+    collections::HashMap,
+    future::Future,
     net::SocketAddr,
     sync::{
-        atomic::{self, AtomicU64},
+        atomic::{self, AtomicBool, AtomicU64},
         Arc, RwLock,
     },
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use crate::exchange::Orderbook;
-use crate::orderbook::{orderbook_aggregator_client::OrderbookAggregatorClient, Empty};
-use crate::run;
+use crate::exchange::{ExchangeConfig, Orderbook};
+use crate::merger::{Books, ExchangeBook, Merger, View, DEFAULT_DEPTH};
+use crate::orderbook::{
+    orderbook_aggregator_client::OrderbookAggregatorClient, Empty, FeedState, LevelView,
+    SummaryRequest,
+};
+use crate::{run, Cli, StartupPolicy};
+use clap::Parser;
+use tonic::{transport::Channel, Code};
 
+// State shared by a mock exchange and its connections
 #[cfg(test)]
-pub struct MockBinance {
-    data: Arc<RwLock<Orderbook>>,
-    port: u16,
+trait MockState: Default + Send + Sync + 'static {
+    // Orders published by the mock
+    fn orders(&self) -> &RwLock<Orderbook>;
 }
 
+// Mock exchange serving its routes on a local port, the venue protocol is implemented on
+// `Mock<State>` of each exchange
 #[cfg(test)]
-impl MockBinance {
-    pub fn start() -> Self {
-        let data = Arc::default();
+struct Mock<S> {
+    data: Arc<S>,
+    port: u16,
+}
 
-        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
-        let app = Router::new()
-            .route("/ws/ethbtc@depth10@100ms", get(Self::ws_handler))
-            .with_state(Arc::clone(&data));
-        let server = axum::Server::bind(&addr)
+#[cfg(test)]
+impl<S: MockState> Mock<S> {
+    // Serve the routes on `port` in the background, any free port for 0
+    fn serve(routes: Router<Arc<S>>, port: u16) -> Self {
+        let data = Arc::<S>::default();
+        let app = routes.with_state(Arc::clone(&data));
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], port)))
             .serve(app.into_make_service_with_connect_info::<SocketAddr>());
         let port = server.local_addr().port();
 
         tokio::spawn(async move {
-            eprintln!("MockBinance listening on {}", server.local_addr());
+            eprintln!(
+                "{} listening on {}",
+                std::any::type_name::<S>(),
+                server.local_addr()
+            );
             server.await.unwrap();
         });
 
         Self { data, port }
     }
 
+    // Route upgrading every request to a websocket handled by `connect`
+    fn websocket<F, Fut>(connect: F) -> MethodRouter<Arc<S>>
+    where
+        F: FnOnce(WebSocket, Arc<S>) -> Fut + Clone + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        get(
+            move |ws: WebSocketUpgrade, State(data): State<Arc<S>>| async move {
+                ws.on_upgrade(move |ws| connect(ws, data))
+            },
+        )
+    }
+
+    // Serve a websocket handled by `connect` on `/`
+    fn serve_websocket<F, Fut>(connect: F) -> Self
+    where
+        F: FnOnce(WebSocket, Arc<S>) -> Fut + Clone + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self::serve(Router::new().route("/", Self::websocket(connect)), 0)
+    }
+
     pub fn url(&self) -> String {
         format!("ws://localhost:{}", self.port)
     }
 
+    pub fn rest_url(&self) -> String {
+        format!("http://localhost:{}", self.port)
+    }
+
     pub fn set_orders(&self, book: Orderbook) {
-        *self.data.write().unwrap() = book;
+        *self.data.orders().write().unwrap() = book;
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct MockBinanceState {
+    // Orders of ethbtc, and of the other trade pairs by name
+    orders: RwLock<Orderbook>,
+    pairs: RwLock<HashMap<String, Orderbook>>,
+    // Last update id published on the diff stream
+    update_id: AtomicU64,
+    // Number of REST snapshots served
+    snapshots: AtomicU64,
+    // Skip an update id on the diff stream once
+    gap: AtomicBool,
+    // Hold the REST snapshot requests without answering
+    stall: AtomicBool,
+    // Paths of the partial depth streams connected to
+    streams: RwLock<Vec<String>>,
+}
+
+#[cfg(test)]
+impl MockState for MockBinanceState {
+    fn orders(&self) -> &RwLock<Orderbook> {
+        &self.orders
+    }
+}
+
+#[cfg(test)]
+impl MockBinanceState {
+    fn pair_orders(&self, pair: &str) -> Orderbook {
+        match pair {
+            "ethbtc" => self.orders.read().unwrap().clone(),
+            pair => self
+                .pairs
+                .read()
+                .unwrap()
+                .get(pair)
+                .cloned()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+type MockBinance = Mock<MockBinanceState>;
+
+#[cfg(test)]
+impl Mock<MockBinanceState> {
+    pub fn start() -> Self {
+        let routes = Router::new()
+            .route("/ws/:stream", get(Self::ws_handler))
+            .route("/api/v3/depth", get(Self::snapshot_handler));
+        Self::serve(routes, 0)
+    }
+
+    pub fn set_pair_orders(&self, pair: &str, book: Orderbook) {
+        self.data
+            .pairs
+            .write()
+            .unwrap()
+            .insert(pair.to_string(), book);
+    }
+
+    pub fn snapshots(&self) -> u64 {
+        self.data.snapshots.load(atomic::Ordering::Relaxed)
+    }
+
+    // Drop the next update of the diff stream
+    pub fn skip_update(&self) {
+        self.data.gap.store(true, atomic::Ordering::Relaxed);
+    }
+
+    pub fn streams(&self) -> Vec<String> {
+        self.data.streams.read().unwrap().clone()
+    }
+
+    pub fn stall_snapshots(&self, stall: bool) {
+        self.data.stall.store(stall, atomic::Ordering::Relaxed);
     }
 
+    // Streams are `<pair>@depth<levels>@100ms`, or `<pair>@depth@100ms` for the diffs
     async fn ws_handler(
         ws: WebSocketUpgrade,
-        State(data): State<Arc<RwLock<Orderbook>>>,
+        uri: Uri,
+        Path(stream): Path<String>,
+        State(data): State<Arc<MockBinanceState>>,
+    ) -> impl IntoResponse {
+        let (pair, kind) = stream.split_once('@').unwrap_or_default();
+        let pair = pair.to_string();
+        if kind == "depth@100ms" {
+            return ws.on_upgrade(|ws| Self::connect_diff_ws(ws, data, pair));
+        }
+        data.streams.write().unwrap().push(uri.path().to_string());
+        ws.on_upgrade(|ws| Self::connect_ws(ws, data, pair))
+    }
+
+    async fn snapshot_handler(
+        Query(query): Query<HashMap<String, String>>,
+        State(data): State<Arc<MockBinanceState>>,
     ) -> impl IntoResponse {
-        ws.on_upgrade(|ws| Self::connect_ws(ws, data))
+        let pair = query.get("symbol").unwrap().to_lowercase();
+        data.snapshots.fetch_add(1, atomic::Ordering::Relaxed);
+        if data.stall.load(atomic::Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        }
+
+        let orders = data.pair_orders(&pair);
+        Json(serde_json::json!({
+            "lastUpdateId": data.update_id.load(atomic::Ordering::Relaxed),
+            "bids": orders.bids,
+            "asks": orders.asks,
+        }))
     }
 
-    async fn connect_ws(mut ws: WebSocket, data: Arc<RwLock<Orderbook>>) {
+    async fn connect_ws(mut ws: WebSocket, data: Arc<MockBinanceState>, pair: String) {
         static COUNTER: AtomicU64 = AtomicU64::new(0);
 
-        eprintln!("MockBinance publishing on new connection");
+        eprintln!("MockBinance publishing {pair} on new connection");
 
         loop {
             let msg = {
-                let data = data.read().unwrap();
+                let data = data.pair_orders(&pair);
                 serde_json::json!({
                     "lastUpdateId": COUNTER.fetch_add(1, atomic::Ordering::Relaxed),
                     "bids": data.bids.iter().collect::<Vec<_>>(),
@@ -89,91 +240,200 @@ impl MockBinance {
             tokio::time::sleep(Duration::from_millis(100)).await;
         }
     }
+
+    // Publishes every level on each update, with zero amount for the removed ones
+    async fn connect_diff_ws(mut ws: WebSocket, data: Arc<MockBinanceState>, pair: String) {
+        eprintln!("MockBinance publishing {pair} diffs on new connection");
+
+        let mut sent = Orderbook::default();
+        loop {
+            let msg = {
+                let orders = data.pair_orders(&pair);
+                let diff = |prev: &[[String; 2]], next: &[[String; 2]]| {
+                    let mut levels = next.to_vec();
+                    for level in prev {
+                        if !next.iter().any(|l| l[0] == level[0]) {
+                            levels.push([level[0].clone(), "0".into()]);
+                        }
+                    }
+                    levels
+                };
+
+                let mut id = data.update_id.fetch_add(1, atomic::Ordering::Relaxed) + 1;
+                if data.gap.swap(false, atomic::Ordering::Relaxed) {
+                    id = data.update_id.fetch_add(1, atomic::Ordering::Relaxed) + 1;
+                }
+                let msg = serde_json::json!({
+                    "e": "depthUpdate",
+                    "E": SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
+                    "s": pair.to_uppercase(),
+                    "U": id,
+                    "u": id,
+                    "b": diff(&sent.bids, &orders.bids),
+                    "a": diff(&sent.asks, &orders.asks),
+                });
+                sent = orders;
+                msg
+            };
+
+            if ws
+                .send(Message::Text(serde_json::to_string(&msg).unwrap()))
+                .await
+                .is_err()
+            {
+                return;
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
 }
 
 #[cfg(test)]
-pub struct MockBitstamp {
-    data: Arc<RwLock<Orderbook>>,
-    port: u16,
+#[derive(Default)]
+struct MockBitstampState {
+    orders: RwLock<Orderbook>,
+    // Number of REST snapshots served
+    snapshots: AtomicU64,
+    // Drop the next update of the diff channel
+    drop_update: AtomicBool,
+    // Keep the connections open without publishing
+    paused: AtomicBool,
+    // Hold the REST snapshot requests without answering
+    stall: AtomicBool,
+}
+
+#[cfg(test)]
+impl MockState for MockBitstampState {
+    fn orders(&self) -> &RwLock<Orderbook> {
+        &self.orders
+    }
 }
 
 #[cfg(test)]
-impl MockBitstamp {
+type MockBitstamp = Mock<MockBitstampState>;
+
+#[cfg(test)]
+impl Mock<MockBitstampState> {
     pub fn start() -> Self {
-        let data = Arc::default();
+        Self::start_on(0)
+    }
 
-        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
-        let app = Router::new()
-            .route("/", get(Self::ws_handler))
-            .with_state(Arc::clone(&data));
-        let server = axum::Server::bind(&addr)
-            .serve(app.into_make_service_with_connect_info::<SocketAddr>());
-        let port = server.local_addr().port();
+    pub fn start_on(port: u16) -> Self {
+        let routes = Router::new()
+            .route("/", Self::websocket(Self::connect_ws))
+            .route("/api/v2/order_book/ethbtc/", get(Self::snapshot_handler));
+        Self::serve(routes, port)
+    }
 
-        tokio::spawn(async move {
-            eprintln!("MockBitstamp listening on {}", server.local_addr());
-            server.await.unwrap();
-        });
+    pub fn snapshots(&self) -> u64 {
+        self.data.snapshots.load(atomic::Ordering::Relaxed)
+    }
 
-        Self { data, port }
+    // Set the orders without publishing the update on the diff channel
+    pub fn set_orders_silently(&self, book: Orderbook) {
+        let mut orders = self.data.orders.write().unwrap();
+        self.data.drop_update.store(true, atomic::Ordering::Relaxed);
+        *orders = book;
     }
 
-    pub fn url(&self) -> String {
-        format!("ws://localhost:{}", self.port)
+    pub fn pause(&self, paused: bool) {
+        self.data.paused.store(paused, atomic::Ordering::Relaxed);
     }
 
-    pub fn set_orders(&self, book: Orderbook) {
-        *self.data.write().unwrap() = book;
+    pub fn stall_snapshots(&self, stall: bool) {
+        self.data.stall.store(stall, atomic::Ordering::Relaxed);
     }
 
-    async fn ws_handler(
-        ws: WebSocketUpgrade,
-        State(data): State<Arc<RwLock<Orderbook>>>,
-    ) -> impl IntoResponse {
-        ws.on_upgrade(|ws| Self::connect_ws(ws, data))
+    async fn snapshot_handler(State(data): State<Arc<MockBitstampState>>) -> impl IntoResponse {
+        data.snapshots.fetch_add(1, atomic::Ordering::Relaxed);
+        if data.stall.load(atomic::Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        }
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let orders = data.orders.read().unwrap();
+        Json(serde_json::json!({
+            "timestamp": timestamp.as_secs().to_string(),
+            "microtimestamp": timestamp.as_micros().to_string(),
+            "bids": orders.bids,
+            "asks": orders.asks,
+        }))
     }
 
-    async fn connect_ws(mut ws: WebSocket, data: Arc<RwLock<Orderbook>>) {
+    async fn connect_ws(mut ws: WebSocket, data: Arc<MockBitstampState>) {
         eprintln!("MockBitstamp new connection");
 
-        loop {
+        let channel = loop {
             if let Some(msg) = ws.recv().await {
                 if let Ok(Message::Text(json)) = msg {
                     if let Ok(json) = serde_json::from_str::<serde_json::Value>(&json) {
+                        let channel = json["data"]["channel"].as_str().unwrap_or_default();
                         if json["event"] == "bts:subscribe"
-                            && json["data"]["channel"] == "order_book_ethbtc"
+                            && (channel == "order_book_ethbtc"
+                                || channel == "diff_order_book_ethbtc")
                         {
-                            break;
+                            break channel.to_string();
                         }
                     }
                 }
             } else {
                 return;
             }
-        }
+        };
 
-        eprintln!("MockBitstamp publishing on new connection");
+        eprintln!("MockBitstamp publishing {channel} on new connection");
 
+        // Diffs publish every level on each update, with zero amount for the removed ones
+        let mut sent = Orderbook::default();
         loop {
+            if data.paused.load(atomic::Ordering::Relaxed) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                continue;
+            }
+
             let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-            let msg = {
-                let data = data.read().unwrap();
-                serde_json::json!({
+            let (msg, dropped) = {
+                let orders = data.orders.read().unwrap();
+                let dropped = data.drop_update.swap(false, atomic::Ordering::Relaxed);
+                let orders = orders.clone();
+                let book = if channel.starts_with("diff_") {
+                    let diff = |prev: &[[String; 2]], next: &[[String; 2]]| {
+                        let mut levels = next.to_vec();
+                        for level in prev {
+                            if !next.iter().any(|l| l[0] == level[0]) {
+                                levels.push([level[0].clone(), "0".into()]);
+                            }
+                        }
+                        levels
+                    };
+                    let book = Orderbook {
+                        bids: diff(&sent.bids, &orders.bids),
+                        asks: diff(&sent.asks, &orders.asks),
+                    };
+                    sent = orders;
+                    book
+                } else {
+                    orders
+                };
+                let msg = serde_json::json!({
                     "data": {
                         "timestamp": timestamp.as_secs().to_string(),
                         "microtimestamp": timestamp.as_micros().to_string(),
-                        "bids": data.bids.iter().collect::<Vec<_>>(),
-                        "asks": data.asks.iter().collect::<Vec<_>>(),
+                        "bids": book.bids,
+                        "asks": book.asks,
                     },
-                    "channel": "order_book_ethbtc",
+                    "channel": channel,
                     "event": "data",
-                })
+                });
+                (msg, dropped)
             };
 
-            if ws
-                .send(Message::Text(serde_json::to_string(&msg).unwrap()))
-                .await
-                .is_err()
+            if !dropped
+                && ws
+                    .send(Message::Text(serde_json::to_string(&msg).unwrap()))
+                    .await
+                    .is_err()
             {
                 return;
             }
@@ -184,85 +444,2681 @@ impl MockBitstamp {
 }
 
 #[cfg(test)]
-macro_rules! assert_level_eq {
-    ($lvl:expr, $name:expr, $price:expr, $amount:expr) => {{
-        let lvl = &$lvl;
-        assert_eq!(lvl.exchange, $name);
-        assert_eq!(lvl.price, $price);
-        assert_eq!(lvl.amount, $amount);
-    }};
+#[derive(Default)]
+struct MockKrakenState {
+    orders: RwLock<Orderbook>,
+    // Number of book subscriptions
+    subscriptions: AtomicU64,
+    // Send a wrong checksum with the next update
+    corrupt: AtomicBool,
 }
 
 #[cfg(test)]
-#[tokio::test]
-async fn test() {
-    let binance = MockBinance::start();
-    binance.set_orders(Orderbook {
-        bids: vec![
-            ["100.0".into(), "5.0".into()],
-            ["99.0".into(), "10.0".into()],
-        ],
-        asks: vec![
-            ["104.0".into(), "9.0".into()],
-            ["106.0".into(), "7.0".into()],
-        ],
-    });
+impl MockState for MockKrakenState {
+    fn orders(&self) -> &RwLock<Orderbook> {
+        &self.orders
+    }
+}
 
-    let bitstamp = MockBitstamp::start();
-    bitstamp.set_orders(Orderbook {
-        bids: vec![["101".into(), "9.0".into()], ["98".into(), "12.0".into()]],
-        asks: vec![["103".into(), "4.0".into()], ["105".into(), "8.0".into()]],
-    });
+#[cfg(test)]
+type MockKraken = Mock<MockKrakenState>;
 
-    let binance_url = binance.url();
-    let bitstamp_url = bitstamp.url();
+#[cfg(test)]
+impl Mock<MockKrakenState> {
+    // Event time of every book
+    const TIMESTAMP: &str = "2023-10-06T17:35:55.440295Z";
 
-    tokio::spawn(async {
-        if let Err(err) = run("ethbtc".into(), binance_url, bitstamp_url, "8091".into()).await {
-            eprintln!("{err}");
+    pub fn start() -> Self {
+        Self::serve_websocket(Self::connect_ws)
+    }
+
+    pub fn subscriptions(&self) -> u64 {
+        self.data.subscriptions.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn corrupt(&self) {
+        self.data.corrupt.store(true, atomic::Ordering::Relaxed);
+    }
+
+    // Checksum of the top 10 levels, with prices at 5 and quantities at 8 decimals
+    fn checksum(book: &Orderbook) -> u32 {
+        let field = |value: &str, precision: usize| {
+            format!("{:.*}", precision, value.parse::<f64>().unwrap())
+                .replace('.', "")
+                .trim_start_matches('0')
+                .to_string()
+        };
+        let payload: String = book
+            .asks
+            .iter()
+            .take(10)
+            .chain(book.bids.iter().take(10))
+            .map(|[price, qty]| field(price, 5) + &field(qty, 8))
+            .collect();
+        crc32fast::hash(payload.as_bytes())
+    }
+
+    fn book_message(kind: &str, book: Orderbook, checksum: u32) -> Message {
+        let levels = |levels: Vec<[String; 2]>| {
+            levels
+                .iter()
+                .map(|[price, qty]| {
+                    serde_json::json!({
+                        "price": price.parse::<f64>().unwrap(),
+                        "qty": qty.parse::<f64>().unwrap(),
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
+        let msg = serde_json::json!({
+            "channel": "book",
+            "type": kind,
+            "data": [{
+                "symbol": "ETH/BTC",
+                "bids": levels(book.bids),
+                "asks": levels(book.asks),
+                "checksum": checksum,
+                "timestamp": Self::TIMESTAMP,
+            }],
+        });
+        Message::Text(msg.to_string())
+    }
+
+    // Publishes every level on each update, with zero quantity for the removed ones
+    async fn connect_ws(mut ws: WebSocket, data: Arc<MockKrakenState>) {
+        eprintln!("MockKraken new connection");
+
+        let mut subscribed = false;
+        let mut sent = Orderbook::default();
+        loop {
+            let msg = tokio::select! {
+                msg = ws.recv() => {
+                    let Some(Ok(Message::Text(text))) = msg else { return };
+                    let json = serde_json::from_str::<serde_json::Value>(&text).unwrap();
+                    match (json["method"].as_str(), json["params"]["channel"].as_str()) {
+                        (Some("subscribe"), Some("instrument")) => {
+                            let msg = serde_json::json!({
+                                "channel": "instrument",
+                                "type": "snapshot",
+                                "data": {
+                                    "assets": [],
+                                    "pairs": [
+                                        {"symbol": "ETH/USD", "price_precision": 2, "qty_precision": 8},
+                                        {"symbol": "ETH/BTC", "price_precision": 5, "qty_precision": 8},
+                                    ],
+                                },
+                            });
+                            Message::Text(msg.to_string())
+                        }
+                        (Some("subscribe"), Some("book")) => {
+                            assert_eq!(json["params"]["symbol"], serde_json::json!(["ETH/BTC"]));
+                            data.subscriptions.fetch_add(1, atomic::Ordering::Relaxed);
+                            subscribed = true;
+                            sent = data.orders.read().unwrap().clone();
+                            Self::book_message("snapshot", sent.clone(), Self::checksum(&sent))
+                        }
+                        (Some("unsubscribe"), Some("book")) => {
+                            subscribed = false;
+                            continue;
+                        }
+                        _ => continue,
+                    }
+                }
+                _ = tokio::time::sleep(Duration::from_millis(100)), if subscribed => {
+                    let orders = data.orders.read().unwrap().clone();
+                    let diff = |prev: &[[String; 2]], next: &[[String; 2]]| {
+                        let mut levels = next.to_vec();
+                        for level in prev {
+                            if !next.iter().any(|l| l[0] == level[0]) {
+                                levels.push([level[0].clone(), "0".into()]);
+                            }
+                        }
+                        levels
+                    };
+                    let update = Orderbook {
+                        bids: diff(&sent.bids, &orders.bids),
+                        asks: diff(&sent.asks, &orders.asks),
+                    };
+                    let mut checksum = Self::checksum(&orders);
+                    if data.corrupt.swap(false, atomic::Ordering::Relaxed) {
+                        checksum += 1;
+                    }
+                    sent = orders;
+                    Self::book_message("update", update, checksum)
+                }
+            };
+
+            if ws.send(msg).await.is_err() {
+                return;
+            }
         }
-    });
+    }
+}
 
-    let mut client = loop {
-        let c = OrderbookAggregatorClient::connect("http://localhost:8091").await;
-        if let Ok(client) = c {
-            break client;
+#[cfg(test)]
+#[derive(Default)]
+struct MockCoinbaseState {
+    orders: RwLock<Orderbook>,
+}
+
+#[cfg(test)]
+impl MockState for MockCoinbaseState {
+    fn orders(&self) -> &RwLock<Orderbook> {
+        &self.orders
+    }
+}
+
+#[cfg(test)]
+type MockCoinbase = Mock<MockCoinbaseState>;
+
+#[cfg(test)]
+impl Mock<MockCoinbaseState> {
+    pub fn start() -> Self {
+        Self::serve_websocket(Self::connect_ws)
+    }
+
+    async fn connect_ws(mut ws: WebSocket, data: Arc<MockCoinbaseState>) {
+        eprintln!("MockCoinbase new connection");
+
+        loop {
+            let Some(Ok(Message::Text(json))) = ws.recv().await else {
+                return;
+            };
+            let json = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+            if json["type"] == "subscribe" {
+                assert_eq!(json["product_ids"], serde_json::json!(["ETH-BTC"]));
+                assert_eq!(json["channels"], serde_json::json!(["level2_batch"]));
+                break;
+            }
         }
-        tokio::time::sleep(Duration::from_millis(10)).await;
-    };
 
-    let mut stream = client
-        .book_summary(Empty {})
-        .await
-        .expect("book_summary")
-        .into_inner();
+        let mut sent = data.orders.read().unwrap().clone();
+        let snapshot = serde_json::json!({
+            "type": "snapshot",
+            "product_id": "ETH-BTC",
+            "bids": sent.bids,
+            "asks": sent.asks,
+        });
+        if ws.send(Message::Text(snapshot.to_string())).await.is_err() {
+            return;
+        }
 
-    let msg = loop {
-        let next = stream.message().await.unwrap();
-        let next = next.expect("stream closed");
+        // Publishes every level on each update, with zero size for the removed ones
+        loop {
+            tokio::time::sleep(Duration::from_millis(100)).await;
 
-        if next.bids.iter().any(|b| b.exchange == "BINANCE")
-            && next.bids.iter().any(|b| b.exchange == "BITSTAMP")
-        {
-            break next;
+            let orders = data.orders.read().unwrap().clone();
+            let changes = |side: &str, prev: &[[String; 2]], next: &[[String; 2]]| {
+                let mut changes: Vec<_> = next
+                    .iter()
+                    .map(|[price, size]| [side.to_string(), price.clone(), size.clone()])
+                    .collect();
+                for [price, _] in prev {
+                    if !next.iter().any(|l| &l[0] == price) {
+                        changes.push([side.to_string(), price.clone(), "0".into()]);
+                    }
+                }
+                changes
+            };
+            let mut all = changes("buy", &sent.bids, &orders.bids);
+            all.extend(changes("sell", &sent.asks, &orders.asks));
+            sent = orders;
+
+            let msg = serde_json::json!({
+                "type": "l2update",
+                "product_id": "ETH-BTC",
+                "changes": all,
+                "time": "2019-08-14T20:42:27.265Z",
+            });
+            if ws.send(Message::Text(msg.to_string())).await.is_err() {
+                return;
+            }
         }
-        tokio::time::sleep(Duration::from_millis(10)).await;
-    };
+    }
+}
 
-    let bids = &msg.bids;
-    let asks = &msg.asks;
-    // println!("BIDS: {:?}", bids);
-    // println!("ASKS: {:?}", asks);
+#[cfg(test)]
+#[derive(Default)]
+struct MockOkxState {
+    orders: RwLock<Orderbook>,
+    // Number of book subscriptions
+    subscriptions: AtomicU64,
+    // Send a wrong checksum with the next update
+    corrupt: AtomicBool,
+    // Skip a sequence id on the next update
+    gap: AtomicBool,
+}
 
-    assert_level_eq!(bids[0], "BITSTAMP", 101.0, 9.0);
-    assert_level_eq!(bids[1], "BINANCE", 100.0, 5.0);
-    assert_level_eq!(bids[2], "BINANCE", 99.0, 10.0);
-    assert_level_eq!(bids[3], "BITSTAMP", 98.0, 12.0);
+#[cfg(test)]
+impl MockState for MockOkxState {
+    fn orders(&self) -> &RwLock<Orderbook> {
+        &self.orders
+    }
+}
 
-    assert_level_eq!(asks[0], "BITSTAMP", 103.0, 4.0);
-    assert_level_eq!(asks[1], "BINANCE", 104.0, 9.0);
-    assert_level_eq!(asks[2], "BITSTAMP", 105.0, 8.0);
-    assert_level_eq!(asks[3], "BINANCE", 106.0, 7.0);
+#[cfg(test)]
+type MockOkx = Mock<MockOkxState>;
 
-    assert_eq!(msg.spread, 103.0 - 101.0);
+#[cfg(test)]
+impl Mock<MockOkxState> {
+    pub fn start() -> Self {
+        Self::serve_websocket(Self::connect_ws)
+    }
+
+    pub fn subscriptions(&self) -> u64 {
+        self.data.subscriptions.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn corrupt(&self) {
+        self.data.corrupt.store(true, atomic::Ordering::Relaxed);
+    }
+
+    pub fn skip_update(&self) {
+        self.data.gap.store(true, atomic::Ordering::Relaxed);
+    }
+
+    // Checksum of the top 25 bids and asks interleaved, as signed integer
+    fn checksum(book: &Orderbook) -> i32 {
+        let mut fields = Vec::new();
+        for i in 0..25 {
+            for levels in [&book.bids, &book.asks] {
+                if let Some([price, size]) = levels.get(i) {
+                    fields.push(format!("{price}:{size}"));
+                }
+            }
+        }
+        crc32fast::hash(fields.join(":").as_bytes()) as i32
+    }
+
+    fn book_message(action: &str, book: &Orderbook, checksum: i32, seq: (i64, i64)) -> Message {
+        // Levels as `[price, size, liquidated orders, orders]`
+        let levels = |levels: &[[String; 2]]| {
+            levels
+                .iter()
+                .map(|[price, size]| serde_json::json!([price, size, "0", "1"]))
+                .collect::<Vec<_>>()
+        };
+        let msg = serde_json::json!({
+            "arg": {"channel": "books", "instId": "ETH-BTC"},
+            "action": action,
+            "data": [{
+                "bids": levels(&book.bids),
+                "asks": levels(&book.asks),
+                "ts": "1597026383085",
+                "checksum": checksum,
+                "prevSeqId": seq.0,
+                "seqId": seq.1,
+            }],
+        });
+        Message::Text(msg.to_string())
+    }
+
+    // Publishes every level on each update, with zero size for the removed ones
+    async fn connect_ws(mut ws: WebSocket, data: Arc<MockOkxState>) {
+        eprintln!("MockOkx new connection");
+
+        let mut subscribed = false;
+        let mut sent = Orderbook::default();
+        let mut seq_id = 0;
+        loop {
+            let msg = tokio::select! {
+                msg = ws.recv() => {
+                    let Some(Ok(Message::Text(text))) = msg else { return };
+                    let json = serde_json::from_str::<serde_json::Value>(&text).unwrap();
+                    assert_eq!(json["args"][0]["instId"], "ETH-BTC");
+                    match json["op"].as_str() {
+                        Some("subscribe") => {
+                            data.subscriptions.fetch_add(1, atomic::Ordering::Relaxed);
+                            subscribed = true;
+                            sent = data.orders.read().unwrap().clone();
+                            seq_id += 10;
+                            Self::book_message("snapshot", &sent, Self::checksum(&sent), (-1, seq_id))
+                        }
+                        Some("unsubscribe") => {
+                            subscribed = false;
+                            continue;
+                        }
+                        _ => continue,
+                    }
+                }
+                _ = tokio::time::sleep(Duration::from_millis(100)), if subscribed => {
+                    let orders = data.orders.read().unwrap().clone();
+                    let diff = |prev: &[[String; 2]], next: &[[String; 2]]| {
+                        let mut levels = next.to_vec();
+                        for level in prev {
+                            if !next.iter().any(|l| l[0] == level[0]) {
+                                levels.push([level[0].clone(), "0".into()]);
+                            }
+                        }
+                        levels
+                    };
+                    let update = Orderbook {
+                        bids: diff(&sent.bids, &orders.bids),
+                        asks: diff(&sent.asks, &orders.asks),
+                    };
+                    let mut checksum = Self::checksum(&orders);
+                    if data.corrupt.swap(false, atomic::Ordering::Relaxed) {
+                        checksum = checksum.wrapping_add(1);
+                    }
+                    let mut prev_seq_id = seq_id;
+                    if data.gap.swap(false, atomic::Ordering::Relaxed) {
+                        prev_seq_id += 1;
+                    }
+                    seq_id = prev_seq_id + 1;
+                    sent = orders;
+                    Self::book_message("update", &update, checksum, (prev_seq_id, seq_id))
+                }
+            };
+
+            if ws.send(msg).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct MockHtxState {
+    orders: RwLock<Orderbook>,
+    // Pongs answering the last ping
+    pongs: AtomicU64,
+}
+
+#[cfg(test)]
+impl MockState for MockHtxState {
+    fn orders(&self) -> &RwLock<Orderbook> {
+        &self.orders
+    }
+}
+
+#[cfg(test)]
+type MockHtx = Mock<MockHtxState>;
+
+#[cfg(test)]
+impl Mock<MockHtxState> {
+    pub fn start() -> Self {
+        Self::serve_websocket(Self::connect_ws)
+    }
+
+    pub fn pongs(&self) -> u64 {
+        self.data.pongs.load(atomic::Ordering::Relaxed)
+    }
+
+    fn gzip(msg: serde_json::Value) -> Message {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(msg.to_string().as_bytes()).unwrap();
+        Message::Binary(encoder.finish().unwrap())
+    }
+
+    // Pings every 200ms and publishes the orders as numbers every 100ms once subscribed
+    async fn connect_ws(mut ws: WebSocket, data: Arc<MockHtxState>) {
+        eprintln!("MockHtx new connection");
+
+        let mut channel = None;
+        let mut ping = 0;
+        let mut seq_num = 0;
+        loop {
+            let msg = tokio::select! {
+                msg = ws.recv() => {
+                    let Some(Ok(Message::Text(text))) = msg else { return };
+                    let json = serde_json::from_str::<serde_json::Value>(&text).unwrap();
+                    if json["pong"] == ping {
+                        data.pongs.fetch_add(1, atomic::Ordering::Relaxed);
+                        continue;
+                    }
+                    let Some(sub) = json["sub"].as_str() else { continue };
+                    assert_eq!(sub, "market.ethbtc.mbp.refresh.10");
+                    channel = Some(sub.to_string());
+                    serde_json::json!({"id": json["id"], "status": "ok", "subbed": sub, "ts": 1})
+                }
+                _ = tokio::time::sleep(Duration::from_millis(100)) => {
+                    if seq_num % 2 == 0 {
+                        ping += 1;
+                        let _ = ws.send(Self::gzip(serde_json::json!({ "ping": ping }))).await;
+                    }
+                    seq_num += 1;
+                    let Some(channel) = &channel else { continue };
+                    let orders = data.orders.read().unwrap().clone();
+                    let levels = |levels: &[[String; 2]]| {
+                        levels
+                            .iter()
+                            .map(|[price, amount]| {
+                                serde_json::json!([
+                                    price.parse::<f64>().unwrap(),
+                                    amount.parse::<f64>().unwrap(),
+                                ])
+                            })
+                            .collect::<Vec<_>>()
+                    };
+                    serde_json::json!({
+                        "ch": channel,
+                        "ts": 1573199608679u64,
+                        "tick": {
+                            "seqNum": seq_num,
+                            "bids": levels(&orders.bids),
+                            "asks": levels(&orders.asks),
+                        },
+                    })
+                }
+            };
+
+            if ws.send(Self::gzip(msg)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct MockBitfinexState {
+    orders: RwLock<Orderbook>,
+    // Number of book subscriptions
+    subscriptions: AtomicU64,
+    // Send a wrong checksum after the next update
+    corrupt: AtomicBool,
+}
+
+#[cfg(test)]
+impl MockState for MockBitfinexState {
+    fn orders(&self) -> &RwLock<Orderbook> {
+        &self.orders
+    }
+}
+
+#[cfg(test)]
+type MockBitfinex = Mock<MockBitfinexState>;
+
+#[cfg(test)]
+impl Mock<MockBitfinexState> {
+    pub fn start() -> Self {
+        Self::serve_websocket(Self::connect_ws)
+    }
+
+    pub fn subscriptions(&self) -> u64 {
+        self.data.subscriptions.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn corrupt(&self) {
+        self.data.corrupt.store(true, atomic::Ordering::Relaxed);
+    }
+
+    fn number(value: &str) -> f64 {
+        value.parse().unwrap()
+    }
+
+    // Levels as `[price, count, amount]` with negative amounts for asks
+    fn levels(book: &Orderbook) -> Vec<serde_json::Value> {
+        let bids = book.bids.iter().map(|[price, amount]| {
+            serde_json::json!([Self::number(price), 1, Self::number(amount)])
+        });
+        let asks = book.asks.iter().map(|[price, amount]| {
+            serde_json::json!([Self::number(price), 1, -Self::number(amount)])
+        });
+        bids.chain(asks).collect()
+    }
+
+    // Checksum of the top 25 bids and asks interleaved, as signed integer
+    fn checksum(book: &Orderbook) -> i32 {
+        let mut fields = Vec::new();
+        for i in 0..25 {
+            if let Some([price, amount]) = book.bids.get(i) {
+                fields.push(format!("{}:{}", Self::number(price), Self::number(amount)));
+            }
+            if let Some([price, amount]) = book.asks.get(i) {
+                fields.push(format!("{}:{}", Self::number(price), -Self::number(amount)));
+            }
+        }
+        crc32fast::hash(fields.join(":").as_bytes()) as i32
+    }
+
+    // Publishes the changed levels one by one, with zero count for the removed ones,
+    // followed by a heartbeat and the checksum
+    async fn connect_ws(mut ws: WebSocket, data: Arc<MockBitfinexState>) {
+        eprintln!("MockBitfinex new connection");
+
+        let info = serde_json::json!({"event": "info", "version": 2});
+        if ws.send(Message::Text(info.to_string())).await.is_err() {
+            return;
+        }
+
+        let mut chan_id = None;
+        let mut sent = Orderbook::default();
+        loop {
+            let msgs = tokio::select! {
+                msg = ws.recv() => {
+                    let Some(Ok(Message::Text(text))) = msg else { return };
+                    let json = serde_json::from_str::<serde_json::Value>(&text).unwrap();
+                    match json["event"].as_str() {
+                        Some("conf") => {
+                            assert_eq!(json["flags"], 131072);
+                            vec![serde_json::json!({"event": "conf", "status": "OK", "flags": 131072})]
+                        }
+                        Some("subscribe") => {
+                            assert_eq!(json["symbol"], "tETHBTC");
+                            assert_eq!(json["prec"], "P1");
+                            assert_eq!(json["len"], "25");
+                            let id = 10 + data.subscriptions.fetch_add(1, atomic::Ordering::Relaxed);
+                            chan_id = Some(id);
+                            sent = data.orders.read().unwrap().clone();
+                            vec![
+                                serde_json::json!({
+                                    "event": "subscribed",
+                                    "channel": "book",
+                                    "chanId": id,
+                                    "symbol": "tETHBTC",
+                                    "prec": "P1",
+                                    "freq": "F0",
+                                    "len": "25",
+                                }),
+                                serde_json::json!([id, Self::levels(&sent)]),
+                            ]
+                        }
+                        Some("unsubscribe") => {
+                            assert_eq!(json["chanId"], serde_json::json!(chan_id));
+                            chan_id = None;
+                            continue;
+                        }
+                        _ => continue,
+                    }
+                }
+                _ = tokio::time::sleep(Duration::from_millis(100)), if chan_id.is_some() => {
+                    let id = chan_id.unwrap();
+                    let orders = data.orders.read().unwrap().clone();
+                    let removed = |prev: &[[String; 2]], next: &[[String; 2]], side: f64| {
+                        prev.iter()
+                            .filter(|level| !next.iter().any(|l| l[0] == level[0]))
+                            .map(|[price, _]| serde_json::json!([id, [Self::number(price), 0, side]]))
+                            .collect::<Vec<_>>()
+                    };
+                    let mut msgs = removed(&sent.bids, &orders.bids, 1.0);
+                    msgs.extend(removed(&sent.asks, &orders.asks, -1.0));
+                    msgs.extend(Self::levels(&orders).into_iter().map(|level| serde_json::json!([id, level])));
+                    msgs.push(serde_json::json!([id, "hb"]));
+
+                    let mut checksum = Self::checksum(&orders);
+                    if data.corrupt.swap(false, atomic::Ordering::Relaxed) {
+                        checksum = checksum.wrapping_add(1);
+                    }
+                    msgs.push(serde_json::json!([id, "cs", checksum]));
+                    sent = orders;
+                    msgs
+                }
+            };
+
+            for msg in msgs {
+                if ws.send(Message::Text(msg.to_string())).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct MockBybitState {
+    orders: RwLock<Orderbook>,
+    // Number of orderbook subscriptions
+    subscriptions: AtomicU64,
+    // Skip an update id on the next delta
+    gap: AtomicBool,
+    // Send a snapshot instead of the next delta, as after a service restart
+    snapshot: AtomicBool,
+}
+
+#[cfg(test)]
+impl MockState for MockBybitState {
+    fn orders(&self) -> &RwLock<Orderbook> {
+        &self.orders
+    }
+}
+
+#[cfg(test)]
+type MockBybit = Mock<MockBybitState>;
+
+#[cfg(test)]
+impl Mock<MockBybitState> {
+    pub fn start() -> Self {
+        Self::serve_websocket(Self::connect_ws)
+    }
+
+    pub fn subscriptions(&self) -> u64 {
+        self.data.subscriptions.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn skip_update(&self) {
+        self.data.gap.store(true, atomic::Ordering::Relaxed);
+    }
+
+    // Send a fresh snapshot without a new subscription, silently changing the orders
+    pub fn restart(&self, book: Orderbook) {
+        let mut orders = self.data.orders.write().unwrap();
+        self.data.snapshot.store(true, atomic::Ordering::Relaxed);
+        *orders = book;
+    }
+
+    fn book_message(kind: &str, book: &Orderbook, update_id: u64) -> Message {
+        let msg = serde_json::json!({
+            "topic": "orderbook.50.ETHBTC",
+            "type": kind,
+            "ts": 1672304484978u64,
+            "data": {
+                "s": "ETHBTC",
+                "b": book.bids,
+                "a": book.asks,
+                "u": update_id,
+                "seq": 7961638724u64 + update_id,
+            },
+            "cts": 1672304484976u64,
+        });
+        Message::Text(msg.to_string())
+    }
+
+    // Publishes every level on each delta, with zero size for the removed ones
+    async fn connect_ws(mut ws: WebSocket, data: Arc<MockBybitState>) {
+        eprintln!("MockBybit new connection");
+
+        let mut subscribed = false;
+        let mut sent = Orderbook::default();
+        let mut update_id = 0;
+        loop {
+            let msg = tokio::select! {
+                msg = ws.recv() => {
+                    let Some(Ok(Message::Text(text))) = msg else { return };
+                    let json = serde_json::from_str::<serde_json::Value>(&text).unwrap();
+                    assert_eq!(json["args"], serde_json::json!(["orderbook.50.ETHBTC"]));
+                    match json["op"].as_str() {
+                        Some("subscribe") => {
+                            data.subscriptions.fetch_add(1, atomic::Ordering::Relaxed);
+                            let ack = serde_json::json!({"success": true, "ret_msg": "", "op": "subscribe"});
+                            if ws.send(Message::Text(ack.to_string())).await.is_err() {
+                                return;
+                            }
+                            subscribed = true;
+                            sent = data.orders.read().unwrap().clone();
+                            update_id += 100;
+                            Self::book_message("snapshot", &sent, update_id)
+                        }
+                        Some("unsubscribe") => {
+                            subscribed = false;
+                            continue;
+                        }
+                        _ => continue,
+                    }
+                }
+                _ = tokio::time::sleep(Duration::from_millis(100)), if subscribed => {
+                    let orders = data.orders.read().unwrap().clone();
+                    if data.snapshot.swap(false, atomic::Ordering::Relaxed) {
+                        update_id = 1;
+                        sent = orders;
+                        Self::book_message("snapshot", &sent, update_id)
+                    } else {
+                        let diff = |prev: &[[String; 2]], next: &[[String; 2]]| {
+                            let mut levels = next.to_vec();
+                            for level in prev {
+                                if !next.iter().any(|l| l[0] == level[0]) {
+                                    levels.push([level[0].clone(), "0".into()]);
+                                }
+                            }
+                            levels
+                        };
+                        let delta = Orderbook {
+                            bids: diff(&sent.bids, &orders.bids),
+                            asks: diff(&sent.asks, &orders.asks),
+                        };
+                        update_id += 1;
+                        if data.gap.swap(false, atomic::Ordering::Relaxed) {
+                            update_id += 1;
+                        }
+                        sent = orders;
+                        Self::book_message("delta", &delta, update_id)
+                    }
+                }
+            };
+
+            if ws.send(msg).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct MockDeribitState {
+    orders: RwLock<Orderbook>,
+    // Number of book subscriptions
+    subscriptions: AtomicU64,
+    // Number of answered heartbeat test requests
+    tests: AtomicU64,
+    // Skip a change id on the next notification
+    gap: AtomicBool,
+}
+
+#[cfg(test)]
+impl MockState for MockDeribitState {
+    fn orders(&self) -> &RwLock<Orderbook> {
+        &self.orders
+    }
+}
+
+#[cfg(test)]
+type MockDeribit = Mock<MockDeribitState>;
+
+#[cfg(test)]
+impl Mock<MockDeribitState> {
+    pub fn start() -> Self {
+        Self::serve_websocket(Self::connect_ws)
+    }
+
+    pub fn subscriptions(&self) -> u64 {
+        self.data.subscriptions.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn tests(&self) -> u64 {
+        self.data.tests.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn skip_update(&self) {
+        self.data.gap.store(true, atomic::Ordering::Relaxed);
+    }
+
+    // Changes as `[action, price, amount]`, deleting the removed levels
+    fn changes(action: &str, prev: &[[String; 2]], next: &[[String; 2]]) -> serde_json::Value {
+        let number = |value: &str| value.parse::<f64>().unwrap();
+        let mut changes: Vec<_> = next
+            .iter()
+            .map(|[price, amount]| serde_json::json!([action, number(price), number(amount)]))
+            .collect();
+        for [price, _] in prev {
+            if !next.iter().any(|l| &l[0] == price) {
+                changes.push(serde_json::json!(["delete", number(price), 0.0]));
+            }
+        }
+        changes.into()
+    }
+
+    fn notification(
+        kind: &str,
+        bids: serde_json::Value,
+        asks: serde_json::Value,
+        ids: (Option<u64>, u64),
+    ) -> Message {
+        let msg = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "subscription",
+            "params": {
+                "channel": "book.ETH-PERPETUAL.100ms",
+                "data": {
+                    "type": kind,
+                    "timestamp": 1554373962454u64,
+                    "instrument_name": "ETH-PERPETUAL",
+                    "prev_change_id": ids.0,
+                    "change_id": ids.1,
+                    "bids": bids,
+                    "asks": asks,
+                },
+            },
+        });
+        Message::Text(msg.to_string())
+    }
+
+    // Sends a heartbeat test request every 200ms and the book changes every 100ms once subscribed
+    async fn connect_ws(mut ws: WebSocket, data: Arc<MockDeribitState>) {
+        eprintln!("MockDeribit new connection");
+
+        let mut heartbeat = false;
+        let mut subscribed = false;
+        let mut sent = Orderbook::default();
+        let mut change_id = 0;
+        let mut ticks = 0;
+        loop {
+            let msgs = tokio::select! {
+                msg = ws.recv() => {
+                    let Some(Ok(Message::Text(text))) = msg else { return };
+                    let json = serde_json::from_str::<serde_json::Value>(&text).unwrap();
+                    assert_eq!(json["jsonrpc"], "2.0");
+                    let response = |result: serde_json::Value| {
+                        Message::Text(serde_json::json!({"jsonrpc": "2.0", "id": json["id"], "result": result}).to_string())
+                    };
+                    match json["method"].as_str().unwrap() {
+                        "public/set_heartbeat" => {
+                            heartbeat = true;
+                            vec![response("ok".into())]
+                        }
+                        "public/test" => {
+                            data.tests.fetch_add(1, atomic::Ordering::Relaxed);
+                            vec![response(serde_json::json!({"version": "1.2.26"}))]
+                        }
+                        "public/subscribe" => {
+                            assert_eq!(json["params"]["channels"], serde_json::json!(["book.ETH-PERPETUAL.100ms"]));
+                            data.subscriptions.fetch_add(1, atomic::Ordering::Relaxed);
+                            subscribed = true;
+                            sent = data.orders.read().unwrap().clone();
+                            change_id += 100;
+                            vec![
+                                response(json["params"]["channels"].clone()),
+                                Self::notification(
+                                    "snapshot",
+                                    Self::changes("new", &[], &sent.bids),
+                                    Self::changes("new", &[], &sent.asks),
+                                    (None, change_id),
+                                ),
+                            ]
+                        }
+                        "public/unsubscribe" => {
+                            subscribed = false;
+                            vec![response(json["params"]["channels"].clone())]
+                        }
+                        method => panic!("Unexpected method {method}"),
+                    }
+                }
+                _ = tokio::time::sleep(Duration::from_millis(100)) => {
+                    ticks += 1;
+                    let mut msgs = Vec::new();
+                    if heartbeat && ticks % 2 == 0 {
+                        let msg = serde_json::json!({"jsonrpc": "2.0", "method": "heartbeat", "params": {"type": "test_request"}});
+                        msgs.push(Message::Text(msg.to_string()));
+                    }
+                    if subscribed {
+                        let orders = data.orders.read().unwrap().clone();
+                        let mut prev_change_id = change_id;
+                        if data.gap.swap(false, atomic::Ordering::Relaxed) {
+                            prev_change_id += 1;
+                        }
+                        change_id = prev_change_id + 1;
+                        msgs.push(Self::notification(
+                            "change",
+                            Self::changes("change", &sent.bids, &orders.bids),
+                            Self::changes("change", &sent.asks, &orders.asks),
+                            (Some(prev_change_id), change_id),
+                        ));
+                        sent = orders;
+                    }
+                    msgs
+                }
+            };
+
+            for msg in msgs {
+                if ws.send(msg).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct MockVenueState {
+    orders: RwLock<Orderbook>,
+    // Pongs answering the last ping
+    pongs: AtomicU64,
+    keepalives: AtomicU64,
+    connections: AtomicU64,
+}
+
+#[cfg(test)]
+impl MockState for MockVenueState {
+    fn orders(&self) -> &RwLock<Orderbook> {
+        &self.orders
+    }
+}
+
+// Venue without an adapter of its own, served through the generic exchange
+#[cfg(test)]
+type MockVenue = Mock<MockVenueState>;
+
+#[cfg(test)]
+impl Mock<MockVenueState> {
+    pub fn start() -> Self {
+        Self::serve_websocket(Self::connect_ws)
+    }
+
+    pub fn pongs(&self) -> u64 {
+        self.data.pongs.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn keepalives(&self) -> u64 {
+        self.data.keepalives.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn connections(&self) -> u64 {
+        self.data.connections.load(atomic::Ordering::Relaxed)
+    }
+
+    // Pings every 200ms and publishes trades and the orders as `{px, qty}` objects every 100ms once subscribed
+    async fn connect_ws(mut ws: WebSocket, data: Arc<MockVenueState>) {
+        eprintln!("MockVenue new connection");
+        data.connections.fetch_add(1, atomic::Ordering::Relaxed);
+
+        let mut subscribed = false;
+        let mut seq = 0;
+        let mut publish = tokio::time::interval(Duration::from_millis(100));
+        loop {
+            let msgs = tokio::select! {
+                msg = ws.recv() => {
+                    let Some(Ok(Message::Text(text))) = msg else { return };
+                    let json = serde_json::from_str::<serde_json::Value>(&text).unwrap();
+                    match json["op"].as_str().unwrap() {
+                        "pong" => {
+                            data.pongs.fetch_add(1, atomic::Ordering::Relaxed);
+                            continue;
+                        }
+                        "keepalive" => {
+                            data.keepalives.fetch_add(1, atomic::Ordering::Relaxed);
+                            continue;
+                        }
+                        "subscribe" => {
+                            assert_eq!(json["args"], serde_json::json!(["depth:ETH_BTC", "trades:ETH_BTC"]));
+                            subscribed = true;
+                            vec![serde_json::json!({"op": "subscribed", "args": json["args"]})]
+                        }
+                        op => panic!("Unexpected op {op}"),
+                    }
+                }
+                _ = publish.tick() => {
+                    let mut msgs = Vec::new();
+                    if seq % 2 == 0 {
+                        msgs.push(serde_json::json!({"op": "ping"}));
+                    }
+                    seq += 1;
+                    if subscribed {
+                        let orders = data.orders.read().unwrap().clone();
+                        let levels = |levels: &[[String; 2]]| {
+                            levels
+                                .iter()
+                                .map(|[price, amount]| serde_json::json!({"px": price, "qty": amount}))
+                                .collect::<Vec<_>>()
+                        };
+                        msgs.push(serde_json::json!({
+                            "topic": "trades:ETH_BTC",
+                            "data": {"trades": [{"px": "1", "qty": "1"}]},
+                        }));
+                        msgs.push(serde_json::json!({
+                            "topic": "depth:ETH_BTC",
+                            "data": {
+                                "ts": 1700000000123u64,
+                                "seq": seq.to_string(),
+                                "bids": levels(&orders.bids),
+                                "asks": levels(&orders.asks),
+                            },
+                        }));
+                    }
+                    msgs
+                }
+            };
+
+            for msg in msgs {
+                if ws.send(Message::Text(msg.to_string())).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+macro_rules! assert_level_eq {
+    ($lvl:expr, $name:expr, $price:expr, $amount:expr) => {{
+        let lvl = &$lvl;
+        assert_eq!(lvl.exchange, $name);
+        assert_eq!(lvl.price, $price);
+        assert_eq!(lvl.amount, $amount);
+    }};
+}
+
+#[cfg(test)]
+fn start_mocks() -> (MockBinance, MockBitstamp) {
+    let binance = MockBinance::start();
+    binance.set_orders(Orderbook {
+        bids: vec![
+            ["100.0".into(), "5.0".into()],
+            ["99.0".into(), "10.0".into()],
+        ],
+        asks: vec![
+            ["104.0".into(), "9.0".into()],
+            ["106.0".into(), "7.0".into()],
+        ],
+    });
+
+    let bitstamp = MockBitstamp::start();
+    bitstamp.set_orders(Orderbook {
+        bids: vec![["101".into(), "9.0".into()], ["98".into(), "12.0".into()]],
+        asks: vec![["103".into(), "4.0".into()], ["105".into(), "8.0".into()]],
+    });
+
+    (binance, bitstamp)
+}
+
+// Run the aggregator against the mocks and connect a client to it
+#[cfg(test)]
+async fn start_server(
+    binance: &MockBinance,
+    bitstamp: &MockBitstamp,
+    port: u16,
+) -> OrderbookAggregatorClient<Channel> {
+    let exchanges = vec![
+        format!("binance,url={}", binance.url()).parse().unwrap(),
+        format!("bitstamp,url={}", bitstamp.url()).parse().unwrap(),
+    ];
+
+    run_server(exchanges, port).await
+}
+
+// Default config serving ethbtc from the exchanges on `port`
+#[cfg(test)]
+fn config(exchanges: Vec<ExchangeConfig>, port: u16) -> Cli {
+    let mut cli = Cli::parse_from(["orderbook-aggregator"]);
+    cli.exchanges = exchanges;
+    cli.port = port.to_string();
+    cli
+}
+
+#[cfg(test)]
+async fn run_server(
+    exchanges: Vec<ExchangeConfig>,
+    port: u16,
+) -> OrderbookAggregatorClient<Channel> {
+    run_config(config(exchanges, port)).await
+}
+
+#[cfg(test)]
+async fn run_config(cli: Cli) -> OrderbookAggregatorClient<Channel> {
+    let port = cli.port.clone();
+    tokio::spawn(async move {
+        if let Err(err) = run(cli).await {
+            eprintln!("{err}");
+        }
+    });
+
+    loop {
+        let c = OrderbookAggregatorClient::connect(format!("http://localhost:{port}")).await;
+        if let Ok(client) = c {
+            break client;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test() {
+    let (binance, bitstamp) = start_mocks();
+    let mut client = start_server(&binance, &bitstamp, 8091).await;
+
+    let mut stream = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner();
+
+    let msg = loop {
+        let next = stream.message().await.unwrap();
+        let next = next.expect("stream closed");
+
+        if next.bids.iter().any(|b| b.exchange == "BINANCE")
+            && next.bids.iter().any(|b| b.exchange == "BITSTAMP")
+        {
+            break next;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+
+    let bids = &msg.bids;
+    let asks = &msg.asks;
+    // println!("BIDS: {:?}", bids);
+    // println!("ASKS: {:?}", asks);
+
+    assert_level_eq!(bids[0], "BITSTAMP", 101.0, 9.0);
+    assert_level_eq!(bids[1], "BINANCE", 100.0, 5.0);
+    assert_level_eq!(bids[2], "BINANCE", 99.0, 10.0);
+    assert_level_eq!(bids[3], "BITSTAMP", 98.0, 12.0);
+
+    assert_level_eq!(asks[0], "BITSTAMP", 103.0, 4.0);
+    assert_level_eq!(asks[1], "BINANCE", 104.0, 9.0);
+    assert_level_eq!(asks[2], "BITSTAMP", 105.0, 8.0);
+    assert_level_eq!(asks[3], "BINANCE", 106.0, 7.0);
+
+    assert_eq!(msg.spread, 103.0 - 101.0);
+    assert_eq!(msg.spread_decimal, "2");
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_symbol_request() {
+    let (binance, bitstamp) = start_mocks();
+    let mut client = start_server(&binance, &bitstamp, 8092).await;
+
+    let status = client
+        .symbol_book_summary(SummaryRequest {
+            symbol: "btcusdt".into(),
+            ..<_>::default()
+        })
+        .await
+        .expect_err("unknown symbol");
+    assert_eq!(status.code(), Code::NotFound);
+
+    let mut stream = client
+        .symbol_book_summary(SummaryRequest {
+            symbol: "ETHBTC".into(),
+            depth: 1,
+            exchanges: vec!["binance".into()],
+            ..<_>::default()
+        })
+        .await
+        .expect("symbol_book_summary")
+        .into_inner();
+
+    let msg = loop {
+        let next = stream.message().await.unwrap();
+        let next = next.expect("stream closed");
+
+        if !next.bids.is_empty() {
+            break next;
+        }
+    };
+
+    assert_eq!(msg.symbol, "ethbtc");
+    assert_eq!(msg.bids.len(), 1);
+    assert_eq!(msg.asks.len(), 1);
+    assert_level_eq!(msg.bids[0], "BINANCE", 100.0, 5.0);
+    assert_level_eq!(msg.asks[0], "BINANCE", 104.0, 9.0);
+    assert_eq!(msg.spread, 104.0 - 100.0);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_trade_pairs() {
+    let (binance, _) = start_mocks();
+    binance.set_pair_orders(
+        "btcusdt",
+        Orderbook {
+            bids: vec![["30000".into(), "1.5".into()]],
+            asks: vec![["30010".into(), "2.5".into()]],
+        },
+    );
+    let mut cli = config(
+        vec![format!("binance,url={}", binance.url()).parse().unwrap()],
+        8114,
+    );
+    cli.trade_pairs = vec!["ethbtc".into(), "btcusdt".into()];
+    let mut client = run_config(cli).await;
+
+    let first = |mut stream: tonic::Streaming<crate::orderbook::Summary>| async move {
+        loop {
+            let next = stream.message().await.unwrap().expect("stream closed");
+            if !next.bids.is_empty() {
+                break next;
+            }
+        }
+    };
+
+    // The first trade pair is served without the symbol metadata
+    let stream = client.book_summary(Empty {}).await.unwrap().into_inner();
+    let msg = first(stream).await;
+    assert_eq!(msg.symbol, "ethbtc");
+    assert_level_eq!(msg.bids[0], "BINANCE", 100.0, 5.0);
+    assert_level_eq!(msg.asks[0], "BINANCE", 104.0, 9.0);
+
+    let mut request = tonic::Request::new(Empty {});
+    request
+        .metadata_mut()
+        .insert("symbol", "btcusdt".parse().unwrap());
+    let stream = client.book_summary(request).await.unwrap().into_inner();
+    let msg = first(stream).await;
+    assert_eq!(msg.symbol, "btcusdt");
+    assert_eq!(msg.bids.len(), 1);
+    assert_level_eq!(msg.bids[0], "BINANCE", 30000.0, 1.5);
+    assert_level_eq!(msg.asks[0], "BINANCE", 30010.0, 2.5);
+
+    let stream = client
+        .symbol_book_summary(SummaryRequest {
+            symbol: "btcusdt".into(),
+            ..<_>::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    let msg = first(stream).await;
+    assert_eq!(msg.symbol, "btcusdt");
+    assert_level_eq!(msg.bids[0], "BINANCE", 30000.0, 1.5);
+
+    let mut request = tonic::Request::new(Empty {});
+    request
+        .metadata_mut()
+        .insert("symbol", "solusdt".parse().unwrap());
+    let status = client.book_summary(request).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_binance_diff() {
+    let (binance, _) = start_mocks();
+    let exchanges = vec![format!(
+        "binance,mode=diff,url={},rest_url={}",
+        binance.url(),
+        binance.rest_url()
+    )
+    .parse()
+    .unwrap()];
+    let mut client = run_server(exchanges, 8093).await;
+
+    let mut stream = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner();
+
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if !next.bids.is_empty() {
+            break next;
+        }
+    };
+    assert_eq!(binance.snapshots(), 1);
+    assert_level_eq!(msg.bids[0], "BINANCE", 100.0, 5.0);
+    assert_level_eq!(msg.bids[1], "BINANCE", 99.0, 10.0);
+    // Update ids of the diff stream are carried with their event time
+    let status = &msg.exchanges[0];
+    assert!(status.exchange_sequence > 0);
+    assert!(status.exchange_time > 0 && status.exchange_time <= status.receive_time);
+    assert_level_eq!(msg.asks[0], "BINANCE", 104.0, 9.0);
+
+    // Removed levels are dropped from the local book
+    binance.set_orders(Orderbook {
+        bids: vec![["99.0".into(), "11.0".into()]],
+        asks: vec![["104.0".into(), "9.0".into()]],
+    });
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids.len() == 1 && next.asks.len() == 1 {
+            break next;
+        }
+    };
+    assert_level_eq!(msg.bids[0], "BINANCE", 99.0, 11.0);
+    assert_level_eq!(msg.asks[0], "BINANCE", 104.0, 9.0);
+
+    // A gap in update ids resyncs from a new snapshot
+    binance.skip_update();
+    while binance.snapshots() < 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let msg = stream.message().await.unwrap().expect("stream closed");
+    assert_level_eq!(msg.bids[0], "BINANCE", 99.0, 11.0);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_snapshot_timeout() {
+    let (binance, bitstamp) = start_mocks();
+    binance.stall_snapshots(true);
+    bitstamp.stall_snapshots(true);
+    let exchanges = vec![
+        format!(
+            "binance,mode=diff,url={},rest_url={},snapshot_timeout_ms=100",
+            binance.url(),
+            binance.rest_url()
+        )
+        .parse()
+        .unwrap(),
+        format!(
+            "bitstamp,mode=diff,url={},rest_url={},snapshot_timeout_ms=100",
+            bitstamp.url(),
+            bitstamp.rest_url()
+        )
+        .parse()
+        .unwrap(),
+    ];
+    let mut cli = config(exchanges, 8115);
+    cli.startup_timeout_ms = 100;
+    let mut client = run_config(cli).await;
+
+    // Stalled snapshots time out and are requested again with the next updates
+    while binance.snapshots() < 3 || bitstamp.snapshots() < 3 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    binance.stall_snapshots(false);
+    bitstamp.stall_snapshots(false);
+
+    let mut stream = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner();
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids.iter().any(|b| b.exchange == "BINANCE")
+            && next.bids.iter().any(|b| b.exchange == "BITSTAMP")
+        {
+            break next;
+        }
+    };
+    assert_level_eq!(msg.bids[0], "BITSTAMP", 101.0, 9.0);
+    assert_level_eq!(msg.bids[1], "BINANCE", 100.0, 5.0);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_bitstamp_diff() {
+    let (_, bitstamp) = start_mocks();
+    let exchanges = vec![format!(
+        "bitstamp,mode=diff,url={},rest_url={}",
+        bitstamp.url(),
+        bitstamp.rest_url()
+    )
+    .parse()
+    .unwrap()];
+    let mut client = run_server(exchanges, 8094).await;
+
+    let mut stream = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner();
+
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if !next.bids.is_empty() {
+            break next;
+        }
+    };
+    assert_eq!(bitstamp.snapshots(), 1);
+    assert_level_eq!(msg.bids[0], "BITSTAMP", 101.0, 9.0);
+    assert_level_eq!(msg.asks[0], "BITSTAMP", 103.0, 4.0);
+
+    // Removed levels are dropped from the local book
+    bitstamp.set_orders(Orderbook {
+        bids: vec![["101".into(), "9.0".into()]],
+        asks: vec![["103".into(), "4.0".into()]],
+    });
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids.len() == 1 && next.asks.len() == 1 {
+            break next;
+        }
+    };
+    assert_level_eq!(msg.bids[0], "BITSTAMP", 101.0, 9.0);
+
+    // Missing the removal of the 103 ask crosses the book and resyncs from a new snapshot
+    bitstamp.set_orders_silently(Orderbook {
+        bids: vec![["104".into(), "1.0".into()]],
+        asks: vec![["105".into(), "2.0".into()]],
+    });
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.asks.len() == 1 && next.asks[0].price == 105.0 {
+            break next;
+        }
+    };
+    assert_eq!(bitstamp.snapshots(), 2);
+    assert_level_eq!(msg.bids[0], "BITSTAMP", 104.0, 1.0);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_bitstamp_resync() {
+    let (_, bitstamp) = start_mocks();
+    let exchanges = vec![format!(
+        "bitstamp,mode=diff,resync_interval_ms=300,url={},rest_url={}",
+        bitstamp.url(),
+        bitstamp.rest_url()
+    )
+    .parse()
+    .unwrap()];
+    let mut client = run_server(exchanges, 8118).await;
+
+    let mut stream = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner();
+
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if !next.bids.is_empty() {
+            break next;
+        }
+    };
+    assert_eq!(msg.bids.len(), 2);
+
+    // Missing the removal of the 98 bid leaves the book uncrossed, only the periodic resync
+    // drops it
+    bitstamp.set_orders_silently(Orderbook {
+        bids: vec![["101".into(), "9.0".into()]],
+        asks: vec![["103".into(), "4.0".into()], ["105".into(), "8.0".into()]],
+    });
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids.len() == 1 {
+            break next;
+        }
+    };
+    assert!(bitstamp.snapshots() >= 2);
+    assert_level_eq!(msg.bids[0], "BITSTAMP", 101.0, 9.0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_decimal_spread() {
+    let book = |bid: &str, ask: &str| {
+        Orderbook {
+            bids: vec![[bid.into(), "1.5".into()]],
+            asks: vec![[ask.into(), "2.50".into()]],
+        }
+        .convert(DEFAULT_DEPTH)
+        .unwrap()
+    };
+    let books = Books {
+        symbol: "ethbtc".into(),
+        sequence: 1,
+        exchanges: [
+            ("BINANCE".into(), ExchangeBook::live(book("0.1", "0.3"))),
+            ("BITSTAMP".into(), ExchangeBook::live(book("0.05", "0.31"))),
+        ]
+        .into(),
+    };
+
+    let msg = Merger::merge(&books, &View::default());
+
+    assert_eq!(msg.spread_decimal, "0.2");
+    assert_eq!(msg.bids[0].price_decimal, "0.1");
+    assert_eq!(msg.asks[0].amount_decimal, "2.50");
+    assert_eq!(msg.asks[1].price_decimal, "0.31");
+    assert_eq!(msg.spread, 0.2);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_subscriber_depth() {
+    let (binance, _) = start_mocks();
+    binance.set_orders(Orderbook {
+        bids: (0..15)
+            .map(|i| [(100 - i).to_string(), "1.0".into()])
+            .collect(),
+        asks: (0..15)
+            .map(|i| [(101 + i).to_string(), "1.0".into()])
+            .collect(),
+    });
+    let exchanges = vec![format!("binance,url={}", binance.url()).parse().unwrap()];
+    let mut client = run_server(exchanges, 8095).await;
+
+    let mut stream = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner();
+    let msg = stream.message().await.unwrap().expect("stream closed");
+    assert_eq!(msg.bids.len(), 10);
+    assert_eq!(binance.streams(), ["/ws/ethbtc@depth10@100ms"]);
+
+    // A deeper subscriber moves Binance to the depth 20 stream
+    let mut deep = client
+        .symbol_book_summary(SummaryRequest {
+            symbol: "ethbtc".into(),
+            depth: 12,
+            ..<_>::default()
+        })
+        .await
+        .expect("symbol_book_summary")
+        .into_inner();
+    // Books parsed before the subscription may still have 10 levels
+    let msg = loop {
+        let next = deep.message().await.unwrap().expect("stream closed");
+        if next.bids.len() > 10 {
+            break next;
+        }
+    };
+    assert_eq!(msg.bids.len(), 12);
+    assert_eq!(msg.asks.len(), 12);
+    assert_level_eq!(msg.bids[11], "BINANCE", 89.0, 1.0);
+
+    while binance.streams().len() < 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(binance.streams()[1], "/ws/ethbtc@depth20@100ms");
+
+    // Other subscribers keep their own depth
+    let msg = stream.message().await.unwrap().expect("stream closed");
+    assert_eq!(msg.bids.len(), 10);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_consolidated_view() {
+    let (binance, bitstamp) = start_mocks();
+    bitstamp.set_orders(Orderbook {
+        bids: vec![["100".into(), "2.5".into()], ["98".into(), "12.0".into()]],
+        asks: vec![["104".into(), "1".into()], ["105".into(), "8.0".into()]],
+    });
+    let mut client = start_server(&binance, &bitstamp, 8096).await;
+
+    let mut stream = client
+        .symbol_book_summary(SummaryRequest {
+            symbol: "ethbtc".into(),
+            depth: 2,
+            view: LevelView::Consolidated.into(),
+            ..<_>::default()
+        })
+        .await
+        .expect("symbol_book_summary")
+        .into_inner();
+
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids.first().is_some_and(|b| b.venues.len() == 2) {
+            break next;
+        }
+    };
+
+    assert_eq!(msg.bids.len(), 2);
+    assert_eq!(msg.bids[0].price_decimal, "100.0");
+    assert_eq!(msg.bids[0].amount_decimal, "7.5");
+    assert_eq!(msg.bids[0].venues[0].exchange, "BINANCE");
+    assert_eq!(msg.bids[0].venues[0].amount_decimal, "5.0");
+    assert_eq!(msg.bids[0].venues[1].exchange, "BITSTAMP");
+    assert_eq!(msg.bids[0].venues[1].amount_decimal, "2.5");
+    assert_eq!(msg.bids[1].price_decimal, "99.0");
+    assert_eq!(msg.bids[1].venues.len(), 1);
+
+    assert_eq!(msg.asks.len(), 2);
+    assert_eq!(msg.asks[0].amount_decimal, "10.0");
+    assert_eq!(msg.asks[0].venues.len(), 2);
+    assert_eq!(msg.asks[1].exchange, "BITSTAMP");
+    assert_eq!(msg.spread_decimal, "4.0");
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_stale_exchange() {
+    let (binance, bitstamp) = start_mocks();
+    let mut cli = config(
+        vec![
+            format!("binance,url={}", binance.url()).parse().unwrap(),
+            format!("bitstamp,url={}", bitstamp.url()).parse().unwrap(),
+        ],
+        8097,
+    );
+    cli.max_age_ms = 500;
+    let mut client = run_config(cli).await;
+
+    let mut stream = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner();
+
+    let state = |msg: &crate::orderbook::Summary, name: &str| {
+        msg.exchanges
+            .iter()
+            .find(|status| status.exchange == name)
+            .map(|status| status.state())
+    };
+    loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids.iter().any(|b| b.exchange == "BITSTAMP") {
+            assert_eq!(state(&next, "BITSTAMP"), Some(FeedState::Live));
+            break;
+        }
+    }
+
+    // A stalled exchange is left out of the merged book
+    bitstamp.pause(true);
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if state(&next, "BITSTAMP") == Some(FeedState::Stale) {
+            break next;
+        }
+        assert!(next.bids.iter().any(|b| b.exchange == "BITSTAMP"));
+    };
+    assert!(msg.bids.iter().all(|b| b.exchange == "BINANCE"));
+    assert!(msg.asks.iter().all(|a| a.exchange == "BINANCE"));
+    assert_eq!(state(&msg, "BINANCE"), Some(FeedState::Live));
+    assert_eq!(msg.spread_decimal, "4.0");
+
+    // And merged again once it publishes
+    bitstamp.pause(false);
+    loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if state(&next, "BITSTAMP") == Some(FeedState::Live) {
+            assert_level_eq!(next.bids[0], "BITSTAMP", 101.0, 9.0);
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_timestamps() {
+    let (binance, bitstamp) = start_mocks();
+    let mut client = start_server(&binance, &bitstamp, 8098).await;
+
+    let mut stream = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner();
+
+    let mut last = stream.message().await.unwrap().expect("stream closed");
+    let mut binance_sequence = 0;
+    let mut bitstamp_time = 0;
+    // Merged books are numbered in order, skipped numbers are books the client lagged behind on.
+    // Exchange timestamps and sequences move forward
+    while binance_sequence == 0 || bitstamp_time == 0 {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        assert!(next.sequence > last.sequence);
+
+        for status in &next.exchanges {
+            let previous = last
+                .exchanges
+                .iter()
+                .find(|s| s.exchange == status.exchange);
+            let Some(previous) = previous.filter(|s| s.receive_time > 0) else {
+                continue;
+            };
+            assert!(status.receive_time >= previous.receive_time);
+            match status.exchange.as_str() {
+                "BINANCE" if status.exchange_sequence > previous.exchange_sequence => {
+                    binance_sequence = status.exchange_sequence;
+                }
+                "BITSTAMP" if status.exchange_time > previous.exchange_time => {
+                    assert!(status.exchange_time <= status.receive_time);
+                    bitstamp_time = status.exchange_time;
+                }
+                _ => {}
+            }
+        }
+        last = next;
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_kraken() {
+    let kraken = MockKraken::start();
+    kraken.set_orders(Orderbook {
+        bids: vec![
+            ["0.0525".into(), "1.5".into()],
+            ["0.05249".into(), "0.00012".into()],
+        ],
+        asks: vec![["0.05251".into(), "3".into()]],
+    });
+    let exchanges = vec![format!("kraken,url={}", kraken.url()).parse().unwrap()];
+    let mut client = run_server(exchanges, 8099).await;
+
+    let mut stream = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner();
+
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if !next.bids.is_empty() {
+            break next;
+        }
+    };
+    assert_eq!(msg.bids[0].exchange, "KRAKEN");
+    assert_eq!(msg.bids[0].price_decimal, "0.0525");
+    assert_eq!(msg.bids[1].amount_decimal, "0.00012");
+    assert_eq!(msg.asks[0].price_decimal, "0.05251");
+    assert_eq!(msg.exchanges[0].exchange_time, 1696613755440295);
+
+    // Updates are checked against the checksum
+    kraken.set_orders(Orderbook {
+        bids: vec![["0.05249".into(), "2".into()]],
+        asks: vec![["0.05251".into(), "3".into()]],
+    });
+    loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids.len() == 1 {
+            assert_eq!(next.bids[0].amount_decimal, "2.0");
+            break;
+        }
+    }
+    assert_eq!(kraken.subscriptions(), 1);
+
+    // A checksum mismatch resubscribes to a new snapshot
+    kraken.corrupt();
+    while kraken.subscriptions() < 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    kraken.set_orders(Orderbook {
+        bids: vec![["0.05248".into(), "1".into()]],
+        asks: vec![["0.05251".into(), "3".into()]],
+    });
+    loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids[0].price_decimal == "0.05248" {
+            assert_eq!(next.bids.len(), 1);
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_symbol_split() {
+    use crate::exchange::symbol::split;
+
+    let pair = |base: &str, quote: &str| Some((base.to_string(), quote.to_string()));
+    assert_eq!(split("ethbtc"), pair("ETH", "BTC"));
+    assert_eq!(split("btcusdt"), pair("BTC", "USDT"));
+    assert_eq!(split("xbt-eur"), pair("XBT", "EUR"));
+    assert_eq!(split("usdt"), None);
+    assert_eq!(split("ethxyz"), None);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_kraken_unknown_pair() {
+    let kraken = MockKraken::start();
+    let exchanges = vec![format!("kraken,symbol=ETH/XBT,url={}", kraken.url())
+        .parse()
+        .unwrap()];
+
+    // The instrument snapshot without the pair closes the connection
+    let mut cli = config(exchanges, 8119);
+    cli.startup_timeout_ms = 500;
+    cli.startup_policy = StartupPolicy::FailFast;
+    let err = run(cli).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "No book within 500ms from KRAKEN ethbtc (Unknown Kraken pair ETH/XBT)"
+    );
+    assert_eq!(kraken.subscriptions(), 0);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_symbol_option() {
+    use crate::exchange::symbol::format;
+
+    // Symbols are set per trade pair, a plain symbol only serves a single pair
+    let keyed = "kraken,symbol.ethbtc=ETH/XBT"
+        .parse::<ExchangeConfig>()
+        .unwrap();
+    assert_eq!(format(&keyed, "ethbtc", "/").unwrap(), "ETH/XBT");
+    assert_eq!(format(&keyed, "btcusdt", "/").unwrap(), "BTC/USDT");
+    let err = format(&keyed, "ethxyz", "/").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Unknown quote currency in ethxyz, set the symbol.ethxyz option"
+    );
+    let plain = "kraken,symbol=ETH/XBT".parse::<ExchangeConfig>().unwrap();
+    assert_eq!(format(&plain, "ethbtc", "/").unwrap(), "ETH/XBT");
+
+    let mut cli = config(vec![plain], 8116);
+    cli.trade_pairs = vec!["ethbtc".into(), "btcusdt".into()];
+    let err = run(cli).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "The symbol option of kraken is for a single trade pair, set symbol.<pair> for each pair"
+    );
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_coinbase() {
+    let (binance, _) = start_mocks();
+    let coinbase = MockCoinbase::start();
+    coinbase.set_orders(Orderbook {
+        bids: vec![
+            ["100.5".into(), "1.0".into()],
+            ["99.0".into(), "3.0".into()],
+        ],
+        asks: vec![["103.5".into(), "2.0".into()]],
+    });
+    let exchanges = vec![
+        format!("binance,url={}", binance.url()).parse().unwrap(),
+        format!("coinbase,url={}", coinbase.url()).parse().unwrap(),
+    ];
+    let mut client = run_server(exchanges, 8100).await;
+
+    let mut stream = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner();
+
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids.iter().any(|b| b.exchange == "BINANCE")
+            && next.bids.iter().any(|b| b.exchange == "COINBASE")
+        {
+            break next;
+        }
+    };
+    assert_level_eq!(msg.bids[0], "COINBASE", 100.5, 1.0);
+    assert_level_eq!(msg.bids[1], "BINANCE", 100.0, 5.0);
+    assert_level_eq!(msg.asks[0], "COINBASE", 103.5, 2.0);
+    assert_level_eq!(msg.asks[1], "BINANCE", 104.0, 9.0);
+
+    // Updates change and remove levels of the snapshot
+    coinbase.set_orders(Orderbook {
+        bids: vec![["99.0".into(), "4.0".into()]],
+        asks: vec![["103.5".into(), "2.0".into()]],
+    });
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids[0].exchange == "BINANCE" {
+            break next;
+        }
+    };
+    let coinbase_bids: Vec<_> = msg
+        .bids
+        .iter()
+        .filter(|b| b.exchange == "COINBASE")
+        .collect();
+    assert_eq!(coinbase_bids.len(), 1);
+    assert_level_eq!(coinbase_bids[0], "COINBASE", 99.0, 4.0);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_okx() {
+    let okx = MockOkx::start();
+    okx.set_orders(Orderbook {
+        bids: vec![
+            ["0.0525".into(), "1.50".into()],
+            ["0.0524".into(), "3".into()],
+        ],
+        asks: vec![["0.05251".into(), "0.2".into()]],
+    });
+    let exchanges = vec![format!("okx,url={}", okx.url()).parse().unwrap()];
+    let mut client = run_server(exchanges, 8101).await;
+
+    let mut stream = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner();
+
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if !next.bids.is_empty() {
+            break next;
+        }
+    };
+    assert_eq!(msg.bids[0].exchange, "OKX");
+    assert_eq!(msg.bids[0].price_decimal, "0.0525");
+    assert_eq!(msg.bids[0].amount_decimal, "1.50");
+    assert_eq!(msg.asks[0].amount_decimal, "0.2");
+    assert_eq!(msg.exchanges[0].exchange_time, 1597026383085000);
+
+    // Updates are checked against the sequence ids and the checksum
+    okx.set_orders(Orderbook {
+        bids: vec![["0.0524".into(), "2".into()]],
+        asks: vec![["0.05251".into(), "0.2".into()]],
+    });
+    loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids.len() == 1 {
+            assert_eq!(next.bids[0].amount_decimal, "2");
+            break;
+        }
+    }
+    assert_eq!(okx.subscriptions(), 1);
+
+    // A checksum mismatch or a sequence gap resubscribes to a new snapshot
+    okx.corrupt();
+    while okx.subscriptions() < 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    okx.skip_update();
+    while okx.subscriptions() < 3 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    okx.set_orders(Orderbook {
+        bids: vec![["0.0523".into(), "1".into()]],
+        asks: vec![["0.05251".into(), "0.2".into()]],
+    });
+    loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids[0].price_decimal == "0.0523" {
+            assert_eq!(next.bids.len(), 1);
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_htx() {
+    let (binance, _) = start_mocks();
+    let htx = MockHtx::start();
+    htx.set_orders(Orderbook {
+        bids: vec![["100.25".into(), "1.5".into()]],
+        asks: vec![["103.75".into(), "0.25".into()]],
+    });
+    let exchanges = vec![
+        format!("binance,url={}", binance.url()).parse().unwrap(),
+        format!("htx,url={}", htx.url()).parse().unwrap(),
+    ];
+    let mut client = run_server(exchanges, 8102).await;
+
+    let mut stream = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner();
+
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids.iter().any(|b| b.exchange == "BINANCE")
+            && next.bids.iter().any(|b| b.exchange == "HTX")
+        {
+            break next;
+        }
+    };
+    assert_level_eq!(msg.bids[0], "HTX", 100.25, 1.5);
+    assert_level_eq!(msg.bids[1], "BINANCE", 100.0, 5.0);
+    assert_level_eq!(msg.asks[0], "HTX", 103.75, 0.25);
+    assert_eq!(msg.spread_decimal, "3.50");
+    let status = msg.exchanges.iter().find(|s| s.exchange == "HTX").unwrap();
+    assert_eq!(status.exchange_time, 1573199608679000);
+    assert!(status.exchange_sequence > 0);
+
+    // Pings are answered with pongs
+    while htx.pongs() < 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_bitfinex() {
+    let bitfinex = MockBitfinex::start();
+    bitfinex.set_orders(Orderbook {
+        bids: vec![
+            ["0.0525".into(), "1.5".into()],
+            ["0.0524".into(), "3".into()],
+        ],
+        asks: vec![["0.0526".into(), "0.25".into()]],
+    });
+    let exchanges = vec![format!("bitfinex,url={},precision=p1", bitfinex.url())
+        .parse()
+        .unwrap()];
+    let mut client = run_server(exchanges, 8103).await;
+
+    let mut stream = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner();
+
+    // Asks come with negative amounts
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if !next.bids.is_empty() && !next.asks.is_empty() {
+            break next;
+        }
+    };
+    assert_level_eq!(msg.bids[0], "BITFINEX", 0.0525, 1.5);
+    assert_level_eq!(msg.bids[1], "BITFINEX", 0.0524, 3.0);
+    assert_level_eq!(msg.asks[0], "BITFINEX", 0.0526, 0.25);
+
+    // Zero count removes a level, updates are checked against the checksum
+    bitfinex.set_orders(Orderbook {
+        bids: vec![["0.0524".into(), "2".into()]],
+        asks: vec![["0.0526".into(), "0.25".into()]],
+    });
+    loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids.len() == 1 && next.bids[0].amount == 2.0 {
+            break;
+        }
+    }
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(bitfinex.subscriptions(), 1);
+
+    // A checksum mismatch resubscribes to a new snapshot
+    bitfinex.corrupt();
+    while bitfinex.subscriptions() < 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    bitfinex.set_orders(Orderbook {
+        bids: vec![["0.0523".into(), "1".into()]],
+        asks: vec![["0.0526".into(), "0.25".into()]],
+    });
+    loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids.first().is_some_and(|b| b.price == 0.0523) {
+            assert_eq!(next.bids.len(), 1);
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_bitfinex_empty_book() {
+    let bitfinex = MockBitfinex::start();
+    let exchanges = vec![format!("bitfinex,url={},precision=p1", bitfinex.url())
+        .parse()
+        .unwrap()];
+    let mut client = run_server(exchanges, 8120).await;
+
+    let mut stream = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner();
+
+    // Updates after an empty snapshot are applied without resubscribing
+    bitfinex.set_orders(Orderbook {
+        bids: vec![["0.0525".into(), "1.5".into()]],
+        asks: vec![["0.0526".into(), "0.25".into()]],
+    });
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if !next.bids.is_empty() && !next.asks.is_empty() {
+            break next;
+        }
+    };
+    assert_level_eq!(msg.bids[0], "BITFINEX", 0.0525, 1.5);
+    assert_level_eq!(msg.asks[0], "BITFINEX", 0.0526, 0.25);
+    assert_eq!(bitfinex.subscriptions(), 1);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_bybit() {
+    let bybit = MockBybit::start();
+    bybit.set_orders(Orderbook {
+        bids: vec![
+            ["0.0525".into(), "1.5".into()],
+            ["0.0524".into(), "3".into()],
+        ],
+        asks: vec![["0.0526".into(), "0.25".into()]],
+    });
+    let exchanges = vec![format!("bybit,url={}", bybit.url()).parse().unwrap()];
+    let mut cli = config(exchanges, 8104);
+    cli.metrics_port = Some(8121);
+    let mut client = run_config(cli).await;
+
+    let mut stream = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner();
+
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if !next.bids.is_empty() {
+            break next;
+        }
+    };
+    assert_level_eq!(msg.bids[0], "BYBIT", 0.0525, 1.5);
+    assert_level_eq!(msg.asks[0], "BYBIT", 0.0526, 0.25);
+    assert_eq!(msg.exchanges[0].exchange_time, 1672304484976000);
+    assert!(msg.exchanges[0].exchange_sequence > 7961638724);
+
+    // Deltas change and remove levels
+    bybit.set_orders(Orderbook {
+        bids: vec![["0.0524".into(), "2".into()]],
+        asks: vec![["0.0526".into(), "0.25".into()]],
+    });
+    loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids.len() == 1 {
+            assert_eq!(next.bids[0].amount_decimal, "2");
+            break;
+        }
+    }
+
+    // A snapshot in the middle of the stream replaces the book
+    bybit.restart(Orderbook {
+        bids: vec![["0.0522".into(), "1".into()]],
+        asks: vec![["0.0527".into(), "1".into()]],
+    });
+    loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.asks[0].price_decimal == "0.0527" {
+            assert_eq!(next.asks.len(), 1);
+            assert_eq!(next.bids.len(), 1);
+            assert_eq!(next.bids[0].price_decimal, "0.0522");
+            break;
+        }
+    }
+    assert_eq!(bybit.subscriptions(), 1);
+    let metrics = reqwest::get("http://localhost:8121/metrics")
+        .await
+        .expect("metrics request")
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics
+        .lines()
+        .any(|line| line == r#"orderbook_resyncs_total{exchange="BYBIT",symbol="ethbtc"} 1"#));
+
+    // Deltas follow the update ids of the new snapshot
+    bybit.set_orders(Orderbook {
+        bids: vec![["0.0522".into(), "3".into()]],
+        asks: vec![["0.0527".into(), "1".into()]],
+    });
+    loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids[0].amount_decimal == "3" {
+            break;
+        }
+    }
+    assert_eq!(bybit.subscriptions(), 1);
+
+    // A gap in update ids resubscribes to a new snapshot
+    bybit.skip_update();
+    while bybit.subscriptions() < 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    bybit.set_orders(Orderbook {
+        bids: vec![["0.0521".into(), "1".into()]],
+        asks: vec![["0.0527".into(), "1".into()]],
+    });
+    loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids[0].price_decimal == "0.0521" {
+            assert_eq!(next.bids.len(), 1);
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_deribit() {
+    let deribit = MockDeribit::start();
+    // Amounts of the perpetual are in USD
+    deribit.set_orders(Orderbook {
+        bids: vec![
+            ["2000".into(), "1000".into()],
+            ["1600".into(), "400".into()],
+        ],
+        asks: vec![["2500".into(), "500".into()]],
+    });
+    let mut cli = config(
+        vec![
+            format!("deribit,url={},symbol=ETH-PERPETUAL", deribit.url())
+                .parse()
+                .unwrap(),
+        ],
+        8105,
+    );
+    cli.trade_pairs = vec!["ethusd".into()];
+    let mut client = run_config(cli).await;
+
+    let mut stream = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner();
+
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if !next.bids.is_empty() {
+            break next;
+        }
+    };
+    assert_eq!(msg.symbol, "ethusd");
+    assert_level_eq!(msg.bids[0], "DERIBIT", 2000.0, 0.5);
+    assert_level_eq!(msg.bids[1], "DERIBIT", 1600.0, 0.25);
+    assert_level_eq!(msg.asks[0], "DERIBIT", 2500.0, 0.2);
+    assert_eq!(msg.exchanges[0].exchange_time, 1554373962454000);
+
+    // Changes update and delete levels
+    deribit.set_orders(Orderbook {
+        bids: vec![["1600".into(), "800".into()]],
+        asks: vec![["2500".into(), "500".into()]],
+    });
+    loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids.len() == 1 {
+            assert_level_eq!(next.bids[0], "DERIBIT", 1600.0, 0.5);
+            break;
+        }
+    }
+
+    // Heartbeat test requests are answered
+    while deribit.tests() < 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(deribit.subscriptions(), 1);
+
+    // A gap in change ids resubscribes to a new snapshot
+    deribit.skip_update();
+    while deribit.subscriptions() < 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    deribit.set_orders(Orderbook {
+        bids: vec![["1250".into(), "100".into()]],
+        asks: vec![["2500".into(), "500".into()]],
+    });
+    loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids[0].price == 1250.0 {
+            assert_level_eq!(next.bids[0], "DERIBIT", 1250.0, 0.08);
+            assert_eq!(next.bids.len(), 1);
+            break;
+        }
+    }
+
+    // Trade pairs map to spot instruments
+    let mut spot = crate::exchange::build(&"deribit".parse().unwrap(), "ethusdc").unwrap();
+    let subscribe = spot.subscribe(DEFAULT_DEPTH);
+    assert!(subscribe[1]
+        .to_text()
+        .unwrap()
+        .contains("book.ETH_USDC.100ms"));
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_generic() {
+    let venue = MockVenue::start();
+    venue.set_orders(Orderbook {
+        bids: vec![["100.5".into(), "2".into()], ["100".into(), "1".into()]],
+        asks: vec![["101".into(), "3".into()]],
+    });
+    let config = serde_json::json!({
+        "name": "venue",
+        "url": "wss://venue.invalid/ws",
+        "symbol": {"separator": "_"},
+        "subscribe": [{"op": "subscribe", "args": ["depth:{symbol}", "trades:{symbol}"]}],
+        "filter": {"/topic": "depth:{symbol}"},
+        "bids": "/data/bids",
+        "asks": "/data/asks",
+        "level": {"price": "px", "amount": "qty"},
+        "event_time": "/data/ts",
+        "sequence": "/data/seq",
+        "ping": {"filter": {"/op": "ping"}, "reply": {"op": "pong"}},
+        "keepalive": {"op": "keepalive"},
+    });
+    let path = std::env::temp_dir().join("orderbook-aggregator-venue.json");
+    std::fs::write(&path, config.to_string()).unwrap();
+    let exchanges = vec![format!(
+        "generic,config={},url={},ping_interval_ms=50",
+        path.display(),
+        venue.url()
+    )
+    .parse()
+    .unwrap()];
+    let mut client = run_server(exchanges, 8106).await;
+
+    let mut stream = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner();
+
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if !next.bids.is_empty() {
+            break next;
+        }
+    };
+    assert_level_eq!(msg.bids[0], "VENUE", 100.5, 2.0);
+    assert_level_eq!(msg.bids[1], "VENUE", 100.0, 1.0);
+    assert_level_eq!(msg.asks[0], "VENUE", 101.0, 3.0);
+    assert_eq!(msg.spread_decimal, "0.5");
+    assert_eq!(msg.exchanges[0].exchange_time, 1700000000123000);
+    assert!(msg.exchanges[0].exchange_sequence > 0);
+
+    // Pings are answered with the configured reply, keepalives are sent every ping interval
+    while venue.pongs() < 2 || venue.keepalives() < 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // Every level of the messages is kept, a deeper subscriber does not reconnect
+    let mut deep = client
+        .symbol_book_summary(SummaryRequest {
+            symbol: "ethbtc".into(),
+            depth: 100,
+            ..<_>::default()
+        })
+        .await
+        .expect("symbol_book_summary")
+        .into_inner();
+    let msg = deep.message().await.unwrap().expect("stream closed");
+    assert_eq!(msg.bids.len(), 2);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(venue.connections(), 1);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_reconnect() {
+    let (binance, _) = start_mocks();
+    // Nothing listens on the port until the mock starts on it
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let exchanges = vec![
+        format!("binance,url={}", binance.url()).parse().unwrap(),
+        format!("bitstamp,url=ws://localhost:{port},backoff_ms=20,max_backoff_ms=50,max_attempts=3,cooldown_ms=500")
+            .parse()
+            .unwrap(),
+    ];
+    let mut cli = config(exchanges, 8107);
+    cli.startup_timeout_ms = 100;
+    let mut client = run_config(cli).await;
+
+    let mut stream = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner();
+    let status = |msg: &crate::orderbook::Summary, name: &str| {
+        msg.exchanges
+            .iter()
+            .find(|s| s.exchange == name)
+            .cloned()
+            .unwrap()
+    };
+
+    // Failures back off until the circuit breaker opens
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if status(&next, "BITSTAMP").state() == FeedState::Down {
+            break next;
+        }
+    };
+    let bitstamp = status(&msg, "BITSTAMP");
+    assert!(bitstamp.consecutive_failures >= 3);
+    assert_eq!(bitstamp.failures, u64::from(bitstamp.consecutive_failures));
+    assert!(bitstamp.next_attempt_time > 0);
+    assert_eq!(status(&msg, "BINANCE").state(), FeedState::Live);
+    assert_eq!(status(&msg, "BINANCE").failures, 0);
+    assert!(msg.bids.iter().all(|b| b.exchange == "BINANCE"));
+
+    // The exchange is back after the cooldown and the circuit breaker closes
+    let bitstamp = MockBitstamp::start_on(port);
+    bitstamp.set_orders(Orderbook {
+        bids: vec![["101".into(), "9.0".into()]],
+        asks: vec![["103".into(), "4.0".into()]],
+    });
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if status(&next, "BITSTAMP").state() == FeedState::Live {
+            break next;
+        }
+    };
+    let bitstamp = status(&msg, "BITSTAMP");
+    assert_eq!(bitstamp.consecutive_failures, 0);
+    assert!(bitstamp.failures >= 3);
+    assert_eq!(bitstamp.reconnects, 0);
+    assert_eq!(bitstamp.next_attempt_time, 0);
+    assert_level_eq!(msg.bids[0], "BITSTAMP", 101.0, 9.0);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_startup_policy() {
+    let (binance, _) = start_mocks();
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let exchanges = vec![
+        format!("binance,url={}", binance.url()).parse().unwrap(),
+        format!("bitstamp,url=ws://localhost:{port}")
+            .parse()
+            .unwrap(),
+    ];
+
+    // Exits when an exchange has no book within the startup timeout
+    let mut cli = config(exchanges.clone(), 8108);
+    cli.startup_timeout_ms = 200;
+    cli.startup_policy = StartupPolicy::FailFast;
+    let err = run(cli).await.unwrap_err();
+    assert!(err
+        .to_string()
+        .starts_with("No book within 200ms from BITSTAMP ethbtc (connection failure"));
+
+    // Serves the ready exchanges once they have their first book
+    let mut cli = config(exchanges, 8108);
+    cli.startup_timeout_ms = 200;
+    let mut client = run_config(cli).await;
+    let msg = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner()
+        .message()
+        .await
+        .unwrap()
+        .expect("stream closed");
+    assert_level_eq!(msg.bids[0], "BINANCE", 100.0, 5.0);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_duplicate_exchange() {
+    let exchanges = vec![
+        "binance".parse().unwrap(),
+        "binance,mode=diff".parse().unwrap(),
+    ];
+    let err = run(config(exchanges, 8113)).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Exchange BINANCE is configured more than once for ethbtc"
+    );
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_duplicate_trade_pair() {
+    let mut cli = config(vec!["binance".parse().unwrap()], 8117);
+    cli.trade_pairs = vec!["ethbtc:10".into(), "ETHBTC:20".into()];
+    let err = run(cli).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Trade pair ethbtc is configured more than once"
+    );
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_idle_timeout() {
+    let (binance, bitstamp) = start_mocks();
+    let exchanges = vec![
+        format!("binance,url={}", binance.url()).parse().unwrap(),
+        format!(
+            "bitstamp,url={},ping_interval_ms=50,idle_timeout_ms=500,backoff_ms=50",
+            bitstamp.url()
+        )
+        .parse()
+        .unwrap(),
+    ];
+    let mut client = run_server(exchanges, 8109).await;
+
+    let mut stream = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner();
+    let status = |msg: &crate::orderbook::Summary| {
+        msg.exchanges
+            .iter()
+            .find(|s| s.exchange == "BITSTAMP")
+            .cloned()
+            .unwrap()
+    };
+    loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if status(&next).state() == FeedState::Live {
+            break;
+        }
+    }
+
+    // The paused mock neither publishes nor answers pings, the connection is re-established
+    bitstamp.pause(true);
+    loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if status(&next).state() == FeedState::Disconnected {
+            assert!(next.bids.iter().all(|b| b.exchange == "BINANCE"));
+            break;
+        }
+    }
+
+    bitstamp.pause(false);
+    let msg = loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if status(&next).state() == FeedState::Live {
+            break next;
+        }
+    };
+    assert!(status(&msg).reconnects >= 1);
+    assert_eq!(status(&msg).consecutive_failures, 0);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_metrics() {
+    let (binance, bitstamp) = start_mocks();
+    let mut cli = config(
+        vec![
+            format!("binance,url={}", binance.url()).parse().unwrap(),
+            format!("bitstamp,url={}", bitstamp.url()).parse().unwrap(),
+        ],
+        8110,
+    );
+    cli.metrics_port = Some(8111);
+    let mut client = run_config(cli).await;
+
+    let mut stream = client
+        .book_summary(Empty {})
+        .await
+        .expect("book_summary")
+        .into_inner();
+    loop {
+        let next = stream.message().await.unwrap().expect("stream closed");
+        if next.bids.iter().any(|b| b.exchange == "BINANCE")
+            && next.bids.iter().any(|b| b.exchange == "BITSTAMP")
+        {
+            break;
+        }
+    }
+
+    let metrics = reqwest::get("http://localhost:8111/metrics")
+        .await
+        .expect("metrics request")
+        .text()
+        .await
+        .unwrap();
+    let value = |name: &str| {
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("missing {name} in {metrics}"))
+            .parse::<f64>()
+            .unwrap()
+    };
+    assert!(value(r#"orderbook_messages_total{exchange="BINANCE",symbol="ethbtc"}"#) >= 1.0);
+    assert!(value(r#"orderbook_messages_total{exchange="BITSTAMP",symbol="ethbtc"}"#) >= 1.0);
+    assert_eq!(value(r#"orderbook_subscribers{symbol="ethbtc"}"#), 1.0);
+    assert_eq!(value(r#"orderbook_spread{symbol="ethbtc"}"#), 103.0 - 101.0);
+    assert!(value(r#"orderbook_merge_seconds_count{symbol="ethbtc"}"#) >= 1.0);
+    assert_eq!(
+        value(r#"orderbook_reconnects_total{exchange="BINANCE",symbol="ethbtc"}"#),
+        0.0
+    );
+
+    // The gauge follows the subscribers
+    drop(stream);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let metrics = reqwest::get("http://localhost:8111/metrics")
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("orderbook_subscribers{symbol=\"ethbtc\"} 0"));
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_health() {
+    use tonic_health::pb::{
+        health_check_response::ServingStatus, health_client::HealthClient, HealthCheckRequest,
+    };
+    use tonic_reflection::pb::{
+        server_reflection_client::ServerReflectionClient,
+        server_reflection_request::MessageRequest, server_reflection_response::MessageResponse,
+        ServerReflectionRequest,
+    };
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let exchanges = vec![format!("bitstamp,url=ws://localhost:{port},backoff_ms=20")
+        .parse()
+        .unwrap()];
+    let mut cli = config(exchanges, 8112);
+    cli.startup_timeout_ms = 100;
+    let _client = run_config(cli).await;
+
+    let channel = Channel::from_static("http://localhost:8112")
+        .connect()
+        .await
+        .unwrap();
+    let mut health = HealthClient::new(channel.clone());
+    let check = |service: &str| HealthCheckRequest {
+        service: service.into(),
+    };
+    let status = health.check(check("")).await.unwrap().into_inner().status();
+    assert_eq!(status, ServingStatus::Serving);
+    let status = health
+        .check(check("orderbook.OrderbookAggregator"))
+        .await
+        .unwrap()
+        .into_inner()
+        .status();
+    assert_eq!(status, ServingStatus::Serving);
+    let err = health.check(check("btcusdt")).await.unwrap_err();
+    assert_eq!(err.code(), Code::NotFound);
+
+    // The trade pair serves once an exchange is live
+    let mut watch = health.watch(check("ethbtc")).await.unwrap().into_inner();
+    let next = watch.message().await.unwrap().expect("stream closed");
+    assert_eq!(next.status(), ServingStatus::NotServing);
+
+    let bitstamp = MockBitstamp::start_on(port);
+    bitstamp.set_orders(Orderbook {
+        bids: vec![["101".into(), "9.0".into()]],
+        asks: vec![["103".into(), "4.0".into()]],
+    });
+    let next = watch.message().await.unwrap().expect("stream closed");
+    assert_eq!(next.status(), ServingStatus::Serving);
+
+    // Reflection lists the services without the proto file
+    let request = ServerReflectionRequest {
+        host: String::new(),
+        message_request: Some(MessageRequest::ListServices(String::new())),
+    };
+    let mut reflection = ServerReflectionClient::new(channel);
+    let mut responses = reflection
+        .server_reflection_info(tokio_stream::iter([request]))
+        .await
+        .unwrap()
+        .into_inner();
+    let response = responses.message().await.unwrap().expect("stream closed");
+    let Some(MessageResponse::ListServicesResponse(list)) = response.message_response else {
+        panic!("unexpected response {response:?}");
+    };
+    let services = list
+        .service
+        .into_iter()
+        .map(|service| service.name)
+        .collect::<Vec<_>>();
+    assert!(services.contains(&"orderbook.OrderbookAggregator".to_string()));
+    assert!(services.contains(&"grpc.health.v1.Health".to_string()));
 }